@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveTime};
+use serde::Deserialize;
+use tokio::time::{self, Instant};
+
+/// Bandwidth limit, either constant ("2M") or depending on the time of day ("08:00-22:00=1M,22:00-08:00=10M").
+/// Times not covered by the schedule are unlimited.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct BandwidthLimit {
+	rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+	/// Time range, None if the limit applies all day
+	time: Option<(NaiveTime, NaiveTime)>,
+	/// Bytes per second
+	rate: u64,
+}
+
+impl BandwidthLimit {
+	/// Limit in bytes per second at the current time (None = unlimited).
+	pub fn current(&self) -> Option<u64> {
+		let now = Local::now().time();
+		self.rules
+			.iter()
+			.find(|rule| match rule.time {
+				None => true,
+				Some((from, to)) if from <= to => from <= now && now < to,
+				Some((from, to)) => now >= from || now < to, // range includes midnight
+			})
+			.map(|rule| rule.rate)
+	}
+}
+
+/// Parse a rate in bytes per second, e.g. "500K" or "2.5M".
+fn parse_rate(text: &str) -> Result<u64> {
+	let text = text.trim();
+	let (number, unit) = match text.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+		Some((i, _)) => text.split_at(i),
+		None => (text, ""),
+	};
+	let factor = match unit.to_ascii_uppercase().trim_end_matches('B').trim_end_matches('I') {
+		"" => 1,
+		"K" => 1 << 10,
+		"M" => 1 << 20,
+		"G" => 1 << 30,
+		_ => return Err(anyhow!("unknown unit {:?}", unit)),
+	};
+	let number = number.trim().parse::<f64>().with_context(|| format!("invalid rate {:?}", text))?;
+	let rate = (number * factor as f64) as u64;
+	if rate == 0 {
+		return Err(anyhow!("bandwidth limit must be positive"));
+	}
+	Ok(rate)
+}
+
+fn parse_time(text: &str) -> Result<NaiveTime> {
+	NaiveTime::parse_from_str(text.trim(), "%H:%M").with_context(|| format!("invalid time {:?}", text))
+}
+
+impl FromStr for BandwidthLimit {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let rules = s
+			.split(',')
+			.map(|rule| match rule.split_once('=') {
+				Some((range, rate)) => {
+					let (from, to) = range.split_once('-').context("time range should look like 08:00-22:00")?;
+					Ok(Rule {
+						time: Some((parse_time(from)?, parse_time(to)?)),
+						rate: parse_rate(rate)?,
+					})
+				},
+				None => Ok(Rule {
+					time: None,
+					rate: parse_rate(rule)?,
+				}),
+			})
+			.collect::<Result<_>>()?;
+		Ok(BandwidthLimit { rules })
+	}
+}
+
+impl TryFrom<String> for BandwidthLimit {
+	type Error = anyhow::Error;
+
+	fn try_from(value: String) -> Result<Self> {
+		value.parse()
+	}
+}
+
+/// Limits the transfer rate of one or more downloads.
+pub struct Throttle {
+	limit: Option<BandwidthLimit>,
+	state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+	/// Bytes that may be transferred without waiting (negative: transfer is ahead of the limit)
+	available: f64,
+	last_update: Instant,
+}
+
+impl Throttle {
+	pub fn new(limit: Option<BandwidthLimit>) -> Self {
+		Throttle {
+			limit,
+			state: Mutex::new(ThrottleState {
+				available: 0.0,
+				last_update: Instant::now(),
+			}),
+		}
+	}
+
+	/// Account for the transferred bytes, waiting if the transfer is too fast.
+	pub async fn consume(&self, bytes: usize) {
+		let rate = match self.limit.as_ref().and_then(BandwidthLimit::current) {
+			Some(rate) => rate as f64,
+			None => return,
+		};
+		let wait = {
+			let mut state = self.state.lock().unwrap();
+			let now = Instant::now();
+			// allow bursts of up to one second
+			state.available = (state.available + (now - state.last_update).as_secs_f64() * rate).min(rate);
+			state.last_update = now;
+			state.available -= bytes as f64;
+			if state.available >= 0.0 {
+				return;
+			}
+			Duration::from_secs_f64(-state.available / rate)
+		};
+		time::sleep(wait).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_rate_units() {
+		assert_eq!(parse_rate("1000").unwrap(), 1000);
+		assert_eq!(parse_rate("500K").unwrap(), 500 << 10);
+		assert_eq!(parse_rate("2M").unwrap(), 2 << 20);
+		assert_eq!(parse_rate("2.5M").unwrap(), 5 << 19);
+		assert_eq!(parse_rate("1G").unwrap(), 1 << 30);
+		assert_eq!(parse_rate(" 2 MB ").unwrap(), 2 << 20);
+		assert_eq!(parse_rate("2mib").unwrap(), 2 << 20);
+	}
+
+	#[test]
+	fn parse_rate_invalid() {
+		assert!(parse_rate("").is_err());
+		assert!(parse_rate("M").is_err());
+		assert!(parse_rate("2T").is_err());
+		assert!(parse_rate("0").is_err());
+		assert!(parse_rate("-1K").is_err());
+	}
+
+	#[test]
+	fn parse_schedule() {
+		let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+		let limit = "08:00-22:00=1M, 22:00-08:00=10M".parse::<BandwidthLimit>().unwrap();
+		assert_eq!(
+			limit.rules,
+			vec![
+				Rule {
+					time: Some((time(8, 0), time(22, 0))),
+					rate: 1 << 20,
+				},
+				Rule {
+					time: Some((time(22, 0), time(8, 0))),
+					rate: 10 << 20,
+				},
+			]
+		);
+		assert_eq!("2M".parse::<BandwidthLimit>().unwrap().current(), Some(2 << 20));
+		assert!("8:00=1M".parse::<BandwidthLimit>().is_err());
+		assert!("08:00-25:00=1M".parse::<BandwidthLimit>().is_err());
+	}
+}
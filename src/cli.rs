@@ -1,17 +1,20 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
-use std::{path::PathBuf, sync::atomic::{AtomicBool, AtomicUsize}};
+use std::{path::PathBuf, process::{Command, Stdio}, str::FromStr, sync::atomic::{AtomicBool, AtomicUsize}};
 
-#[cfg(feature = "keyring-auth")]
-use anyhow::anyhow;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 #[cfg(feature = "keyring-auth")]
 use colored::Colorize as _;
-use indicatif::ProgressBar;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
 use once_cell::sync::Lazy;
+use serde::Deserialize;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
+use crate::bandwidth::BandwidthLimit;
+use crate::config::Override;
+use crate::instance::{Instance, LoginMethod};
+
+#[derive(Debug, Clone, StructOpt)]
 #[structopt(name = env!("CARGO_PKG_NAME"))]
 pub struct Opt {
 	/// Do not download files
@@ -38,12 +41,28 @@ pub struct Opt {
 	#[structopt(long)]
 	pub check_videos: bool,
 
+	/// Move files no longer available on ILIAS into .ilias-removed
+	#[structopt(long)]
+	pub archive_removed: bool,
+
+	/// Naming of updated files: suffix (name_v2.pdf), subfolder (old versions in .versions) or rename (old versions renamed to name_v1_<date>.pdf)
+	#[structopt(long, default_value = "suffix", possible_values = &["suffix", "subfolder", "rename"])]
+	pub file_versions: FileVersions,
+
+	/// Download all previous versions of files
+	#[structopt(long)]
+	pub all_versions: bool,
+
+	/// Only list the files that would be downloaded
+	#[structopt(long)]
+	pub dry_run: bool,
+
 	/// Verbose logging
 	#[structopt(short, multiple = true, parse(from_occurrences))]
 	pub verbose: usize,
 
 	/// Output directory
-	#[structopt(short, long, parse(from_os_str))]
+	#[structopt(short, long, parse(from_os_str), default_value = "", hide_default_value = true)]
 	pub output: PathBuf,
 
 	/// Parallel download jobs
@@ -59,25 +78,128 @@ pub struct Opt {
 	#[cfg(feature = "keyring-auth")]
 	pub keyring: bool,
 
-	/// KIT account username
-	#[structopt(short = "U", long)]
+	/// ILIAS installation to use: kit or an instance defined in the config file
+	#[structopt(long, default_value = "kit")]
+	pub instance: String,
+
+	/// Login method, overrides the setting of the instance: shibboleth or local (ILIAS login form)
+	#[structopt(long, possible_values = &["shibboleth", "local"])]
+	pub login_method: Option<LoginMethod>,
+
+	/// Account username
+	#[structopt(short = "U", long, env = "ILIAS_USERNAME")]
 	pub username: Option<String>,
 
-	/// KIT account password
-	#[structopt(short = "P", long)]
+	/// Account password
+	#[structopt(short = "P", long, env = "ILIAS_PASSWORD", hide_env_values = true)]
 	pub password: Option<String>,
 
+	/// Command printing the password, e.g. "pass show kit"
+	#[structopt(long)]
+	pub password_command: Option<String>,
+
+	/// Base32 encoded TOTP secret, used to generate the one-time code if two-factor authentication is enabled
+	#[structopt(long)]
+	pub totp_secret: Option<String>,
+
+	/// Session cookie copied from the browser (e.g. PHPSESSID=..), skips the login
+	#[structopt(long, number_of_values = 1)]
+	pub session_cookie: Vec<String>,
+
+	/// Cookies exported from the browser in Netscape cookies.txt format, skips the login
+	#[structopt(long, parse(from_os_str))]
+	pub cookies_file: Option<PathBuf>,
+
 	/// ILIAS page to download
 	#[structopt(long)]
 	pub sync_url: Option<String>,
 
-	/// Requests per minute
+	/// ILIAS requests per minute (pages and file downloads)
 	#[structopt(long, default_value = "8")]
-	pub rate: usize
+	pub rate: usize,
+
+	/// ILIAS file downloads per minute (also limited by --rate)
+	#[structopt(long, default_value = "8")]
+	pub download_rate: usize,
+
+	/// Requests per minute to other hosts (Opencast videos), per host
+	#[structopt(long, default_value = "30")]
+	pub media_rate: usize,
+
+	/// Maximum number of requests sent at once after being idle (per rate limit)
+	#[structopt(long, default_value = "3")]
+	pub burst: usize,
+
+	/// Maximum total download speed in bytes per second (e.g. 2M), optionally depending on the time of day (e.g. 08:00-22:00=1M,22:00-08:00=10M)
+	#[structopt(long)]
+	pub max_bandwidth: Option<BandwidthLimit>,
+
+	/// Maximum speed of every single download (same format as --max-bandwidth)
+	#[structopt(long)]
+	pub max_bandwidth_per_download: Option<BandwidthLimit>,
+
+	/// Maximum attempts for requests failing with timeouts, connection resets or HTTP status 429/502/503/504
+	#[structopt(long, default_value = "5")]
+	pub max_attempts: usize,
+
+	/// Connect timeout in seconds
+	#[structopt(long, default_value = "30")]
+	pub connect_timeout: u64,
+
+	/// Timeout for loading pages in seconds
+	#[structopt(long, default_value = "120")]
+	pub request_timeout: u64,
+
+	/// Abort (and resume) downloads that receive no data for this many seconds
+	#[structopt(long, default_value = "60")]
+	pub stall_timeout: u64,
+
+	/// Write a JSON report of all processed objects to this file
+	#[structopt(long, parse(from_os_str))]
+	pub report: Option<PathBuf>,
+
+	/// Write a JSON line for every processed object to this file
+	#[structopt(long, parse(from_os_str))]
+	pub report_events: Option<PathBuf>,
+
+	/// Per-course settings from the config file
+	#[structopt(skip)]
+	pub overrides: Vec<Override>,
+
+	/// ILIAS instances defined in the config file
+	#[structopt(skip)]
+	pub instances: Vec<Instance>,
+}
+
+/// How to store multiple versions of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileVersions {
+	/// Every version gets its own file: name_v2.pdf
+	Suffix,
+	/// The latest version is stored as name.pdf, older versions in the .versions subfolder
+	Subfolder,
+	/// The latest version is stored as name.pdf, older versions are renamed to name_v1_<date>.pdf
+	Rename,
+}
+
+impl FromStr for FileVersions {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"suffix" => Ok(FileVersions::Suffix),
+			"subfolder" => Ok(FileVersions::Subfolder),
+			"rename" => Ok(FileVersions::Rename),
+			_ => Err(anyhow!("unknown file version mode {:?}", s)),
+		}
+	}
 }
 
 pub static LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
 pub static PROGRESS_BAR_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Draws the overall progress bar and one bar per active download, see [`crate::progress`]
+pub static MULTI_PROGRESS: Lazy<MultiProgress> = Lazy::new(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr_with_hz(10)));
 pub static PROGRESS_BAR: Lazy<ProgressBar> = Lazy::new(|| ProgressBar::new(0));
 
 macro_rules! log {
@@ -132,50 +254,97 @@ macro_rules! error {
 	};
 }
 
+/// Ask for the credentials not given in the options.
+/// The username is taken from --username / ILIAS_USERNAME, the .iliaslogin file or an interactive prompt.
+/// The password is taken from (in order of precedence): --password / ILIAS_PASSWORD, the password command,
+/// the .iliaslogin file (if it belongs to the same user), the system keyring (if enabled) or an interactive prompt.
+/// Only prompted passwords are stored in the keyring.
 pub fn ask_user_pass(opt: &Opt) -> Result<(String, String)> {
+	let login_file = read_login_file(opt)?;
 	let user = if let Some(username) = opt.username.as_ref() {
 		username.clone()
+	} else if let Some((username, _)) = login_file.as_ref() {
+		username.clone()
 	} else {
 		rprompt::prompt_reply_stdout("Username: ").context("username prompt")?
 	};
-	#[cfg(feature = "keyring-auth")]
-	let (pass, should_store);
-	#[cfg(feature = "keyring-auth")]
-	let keyring = Lazy::new(|| keyring::Keyring::new(env!("CARGO_PKG_NAME"), &user));
-	#[cfg(not(feature = "keyring-auth"))]
-	let pass;
-	cfg_if::cfg_if! { // TODO: deduplicate the logic below
-		if #[cfg(feature = "keyring-auth")] {
-			if let Some(password) = opt.password.as_ref() {
-				pass = password.clone();
-				should_store = true;
-			} else if opt.keyring {
-				match keyring.get_password() {
-					Ok(password) => {
-						pass = password;
-						should_store = false;
-					},
-					Err(e) => {
-						error!(e);
-						pass = rpassword::read_password_from_tty(Some("Password: ")).context("password prompt")?;
-						should_store = true;
-					}
-				}
-			} else {
-				pass = rpassword::read_password_from_tty(Some("Password: ")).context("password prompt")?;
-				should_store = true;
-			}
-		} else {
-			if let Some(password) = opt.password.as_ref() {
-				pass = password.clone();
-			} else {
-				pass = rpassword::read_password_from_tty(Some("Password: ")).context("password prompt")?;
-			}
-		}
+	let pass = if let Some(password) = opt.password.as_ref() {
+		password.clone()
+	} else if let Some(command) = opt.password_command.as_ref() {
+		password_from_command(command)?
+	} else if let Some((_, password)) = login_file.filter(|(username, _)| *username == user) {
+		password
+	} else if let Some(password) = saved_password(opt, &user) {
+		password
+	} else {
+		let password = rpassword::read_password_from_tty(Some("Password: ")).context("password prompt")?;
+		save_password(opt, &user, &password)?;
+		password
 	};
-	#[cfg(feature = "keyring-auth")]
-	if should_store && opt.keyring {
-		keyring.set_password(&pass).map_err(|x| anyhow!(x.to_string()))?;
-	}
 	Ok((user, pass))
 }
+
+/// Username and password stored in the .iliaslogin file (in the output folder), if it exists.
+fn read_login_file(opt: &Opt) -> Result<Option<(String, String)>> {
+	let login = match std::fs::read_to_string(opt.output.join(".iliaslogin")) {
+		Ok(login) => login,
+		Err(_) => return Ok(None),
+	};
+	let mut lines = login.split('\n');
+	let user = lines.next().context("missing user in .iliaslogin")?;
+	let pass = lines.next().context("missing password in .iliaslogin")?;
+	Ok(Some((user.trim().to_owned(), pass.trim().to_owned())))
+}
+
+/// Run the command and use the first line of its output as password.
+fn password_from_command(command: &str) -> Result<String> {
+	let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+	let output = Command::new(shell)
+		.args([flag, command])
+		// allow the command to ask for a passphrase
+		.stdin(Stdio::inherit())
+		.stderr(Stdio::inherit())
+		.output()
+		.context("failed to run password command")?;
+	if !output.status.success() {
+		return Err(anyhow!("password command failed ({})", output.status));
+	}
+	let stdout = String::from_utf8(output.stdout).context("output of password command is not valid UTF-8")?;
+	Ok(stdout.lines().next().unwrap_or_default().to_owned())
+}
+
+/// Password stored in the system keyring (if enabled using --keyring).
+#[cfg(feature = "keyring-auth")]
+fn saved_password(opt: &Opt, user: &str) -> Option<String> {
+	if !opt.keyring {
+		return None;
+	}
+	match keyring::Keyring::new(env!("CARGO_PKG_NAME"), user).get_password() {
+		Ok(password) => Some(password),
+		Err(e) => {
+			error!(e);
+			None
+		},
+	}
+}
+
+#[cfg(not(feature = "keyring-auth"))]
+fn saved_password(_opt: &Opt, _user: &str) -> Option<String> {
+	None
+}
+
+/// Store the password in the system keyring (if enabled using --keyring).
+#[cfg(feature = "keyring-auth")]
+fn save_password(opt: &Opt, user: &str, pass: &str) -> Result<()> {
+	if opt.keyring {
+		keyring::Keyring::new(env!("CARGO_PKG_NAME"), user)
+			.set_password(pass)
+			.map_err(|x| anyhow!(x.to_string()))?;
+	}
+	Ok(())
+}
+
+#[cfg(not(feature = "keyring-auth"))]
+fn save_password(_opt: &Opt, _user: &str, _pass: &str) -> Result<()> {
+	Ok(())
+}
@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::bandwidth::BandwidthLimit;
+use crate::cli::{FileVersions, Opt};
+use crate::instance::{self, Instance, LoginMethod};
+
+/// Contents of a config file.
+/// Mirrors the command line options, which take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+	skip_files: Option<bool>,
+	no_videos: Option<bool>,
+	forum: Option<bool>,
+	force: Option<bool>,
+	content_tree: Option<bool>,
+	check_videos: Option<bool>,
+	archive_removed: Option<bool>,
+	file_versions: Option<FileVersions>,
+	all_versions: Option<bool>,
+	verbose: Option<usize>,
+	output: Option<PathBuf>,
+	jobs: Option<usize>,
+	proxy: Option<String>,
+	instance: Option<String>,
+	login_method: Option<LoginMethod>,
+	#[cfg(feature = "keyring-auth")]
+	keyring: Option<bool>,
+	username: Option<String>,
+	password: Option<String>,
+	password_command: Option<String>,
+	totp_secret: Option<String>,
+	session_cookie: Option<Vec<String>>,
+	cookies_file: Option<PathBuf>,
+	sync_url: Option<String>,
+	rate: Option<usize>,
+	download_rate: Option<usize>,
+	media_rate: Option<usize>,
+	burst: Option<usize>,
+	max_bandwidth: Option<BandwidthLimit>,
+	max_bandwidth_per_download: Option<BandwidthLimit>,
+	max_attempts: Option<usize>,
+	connect_timeout: Option<u64>,
+	request_timeout: Option<u64>,
+	stall_timeout: Option<u64>,
+	report: Option<PathBuf>,
+	report_events: Option<PathBuf>,
+	/// Per-course / per-folder settings
+	#[serde(default, rename = "course")]
+	overrides: Vec<Override>,
+	/// Additional ILIAS instances
+	#[serde(default)]
+	instances: Vec<Instance>,
+}
+
+/// Settings that apply to a course or folder (and everything in it).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+	/// Path relative to the output directory
+	pub path: PathBuf,
+	pub skip_files: Option<bool>,
+	pub no_videos: Option<bool>,
+	pub forum: Option<bool>,
+	pub force: Option<bool>,
+	pub check_videos: Option<bool>,
+	pub file_versions: Option<FileVersions>,
+	pub all_versions: Option<bool>,
+	/// Request rates, only lower rates have an effect (see [`Rates`](crate::ratelimit::Rates))
+	pub rate: Option<usize>,
+	pub media_rate: Option<usize>,
+}
+
+impl Config {
+	fn load(path: &Path) -> Result<Option<Self>> {
+		let text = match std::fs::read_to_string(path) {
+			Ok(text) => text,
+			Err(_) => return Ok(None),
+		};
+		let config = toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))?;
+		Ok(Some(config))
+	}
+
+	/// Apply the settings not given on the command line (or using environment variables).
+	/// Config files loaded later override the values of earlier ones.
+	fn apply(self, opt: &mut Opt, matches: &structopt::clap::ArgMatches) {
+		macro_rules! merge {
+			($($field:ident),+) => {$(
+				if let Some(value) = self.$field {
+					if matches.occurrences_of(stringify!($field).replace('_', "-")) == 0 {
+						opt.$field = value;
+					}
+				}
+			)+};
+			// no default values: present only if given on the command line or as environment variable
+			(optional: $($field:ident),+) => {$(
+				if self.$field.is_some() && !matches.is_present(stringify!($field).replace('_', "-")) {
+					opt.$field = self.$field;
+				}
+			)+};
+		}
+		merge!(skip_files, no_videos, forum, force, content_tree, check_videos, archive_removed, file_versions, all_versions);
+		merge!(verbose, output, jobs, instance, session_cookie, rate, download_rate, media_rate, burst, max_attempts, connect_timeout, request_timeout, stall_timeout);
+		#[cfg(feature = "keyring-auth")]
+		merge!(keyring);
+		merge!(optional: proxy, login_method, username, password, password_command, totp_secret, cookies_file, sync_url, report, report_events, max_bandwidth, max_bandwidth_per_download);
+		// config files loaded later are more specific
+		opt.overrides.extend(self.overrides);
+		opt.instances.extend(self.instances);
+	}
+}
+
+/// Parse the command line options and merge them with the config files:
+/// `.iliasconfig.toml` in the output directory and `KIT-ILIAS-downloader/config.toml` in the user config directory.
+pub fn load_options() -> Result<Opt> {
+	let matches = Opt::clap().get_matches();
+	let mut opt = Opt::from_clap(&matches);
+	if let Some(dir) = dirs::config_dir() {
+		if let Some(config) = Config::load(&dir.join(env!("CARGO_PKG_NAME")).join("config.toml"))? {
+			config.apply(&mut opt, &matches);
+		}
+	}
+	if opt.output.as_os_str().is_empty() {
+		return Err(anyhow!("no output directory specified (use --output or set output in the config file)"));
+	}
+	if let Some(config) = Config::load(&opt.output.join(".iliasconfig.toml"))? {
+		config.apply(&mut opt, &matches);
+	}
+	// more specific paths are applied last
+	opt.overrides.sort_by_key(|x| x.path.components().count());
+	instance::init(&opt)?;
+	Ok(opt)
+}
+
+impl Opt {
+	/// Whether a session of the browser is used instead of logging in.
+	pub fn browser_session(&self) -> bool {
+		!self.session_cookie.is_empty() || self.cookies_file.is_some()
+	}
+
+	/// Settings for the specified path (relative to the output directory).
+	pub fn for_path(&self, path: &Path) -> Cow<'_, Opt> {
+		let mut opt = Cow::Borrowed(self);
+		for o in self.overrides.iter().filter(|x| path.starts_with(&x.path)) {
+			let opt = opt.to_mut();
+			macro_rules! apply {
+				($($field:ident),+) => {$(
+					if let Some(value) = o.$field {
+						opt.$field = value;
+					}
+				)+};
+			}
+			apply!(skip_files, no_videos, forum, force, check_videos, file_versions, all_versions, rate, media_rate);
+		}
+		opt
+	}
+}
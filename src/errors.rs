@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use colored::Colorize;
+
+use crate::ilias::IliasError;
+
+/// Kind of failure, used for the summary and the exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+	Auth,
+	Network,
+	Parse,
+	Filesystem,
+}
+
+/// Ordered by priority when choosing the exit code
+const CATEGORIES: [Category; 4] = [
+	Category::Auth,
+	Category::Filesystem,
+	Category::Network,
+	Category::Parse,
+];
+
+static COUNTS: [AtomicUsize; 4] = [
+	AtomicUsize::new(0),
+	AtomicUsize::new(0),
+	AtomicUsize::new(0),
+	AtomicUsize::new(0),
+];
+
+impl Category {
+	/// Find the category of an error by looking at its causes.
+	pub fn of(error: &anyhow::Error) -> Self {
+		for cause in error.chain() {
+			if let Some(e) = cause.downcast_ref::<IliasError>() {
+				match e {
+					IliasError::Auth(_) | IliasError::Credentials(_) | IliasError::SessionExpired(_) => return Category::Auth,
+					IliasError::Relogin(_) => continue, // categorized by the cause
+					IliasError::ErrorPage(_) | IliasError::PageStructure(_) => return Category::Parse,
+					IliasError::Status { status, .. } => {
+						return match status.as_u16() {
+							401 | 403 => Category::Auth,
+							_ => Category::Network,
+						}
+					},
+					IliasError::Stalled(_) | IliasError::Interrupted | IliasError::Http(_) => return Category::Network,
+					IliasError::Io(_) => return Category::Filesystem,
+				}
+			}
+			if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+				return match e.status().map(|x| x.as_u16()) {
+					Some(401) | Some(403) => Category::Auth,
+					_ => Category::Network,
+				};
+			}
+			if let Some(e) = cause.downcast_ref::<io::Error>() {
+				return match e.kind() {
+					io::ErrorKind::TimedOut | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::UnexpectedEof => Category::Network,
+					_ => Category::Filesystem,
+				};
+			}
+			if cause.is::<serde_json::Error>() || cause.is::<url::ParseError>() || cause.is::<std::num::ParseIntError>() {
+				return Category::Parse;
+			}
+		}
+		// remaining errors are mostly caused by unexpected page content
+		Category::Parse
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Category::Auth => "auth",
+			Category::Network => "network",
+			Category::Parse => "parse",
+			Category::Filesystem => "filesystem",
+		}
+	}
+
+	/// Exit code, see sysexits.h
+	pub fn exit_code(self) -> i32 {
+		match self {
+			Category::Auth => 77,       // EX_NOPERM
+			Category::Network => 69,    // EX_UNAVAILABLE
+			Category::Parse => 65,      // EX_DATAERR
+			Category::Filesystem => 74, // EX_IOERR
+		}
+	}
+
+	fn count(self) -> &'static AtomicUsize {
+		&COUNTS[self as usize]
+	}
+}
+
+/// Count the error in its category.
+pub fn record(error: &anyhow::Error) -> Category {
+	let category = Category::of(error);
+	category.count().fetch_add(1, Ordering::SeqCst);
+	category
+}
+
+/// Print the number of errors per category (if there were any).
+pub fn print_summary() {
+	if CATEGORIES.iter().all(|x| x.count().load(Ordering::SeqCst) == 0) {
+		return;
+	}
+	log!(0, "{}", "Errors:".bright_red());
+	for category in &CATEGORIES {
+		let count = category.count().load(Ordering::SeqCst);
+		if count > 0 {
+			log!(0, "  {:<12}{:>5}", category.name(), count);
+		}
+	}
+}
+
+/// Exit code of the most important error category encountered, 0 if there were no errors.
+pub fn exit_code() -> i32 {
+	CATEGORIES
+		.iter()
+		.find(|x| x.count().load(Ordering::SeqCst) > 0)
+		.map(|x| x.exit_code())
+		.unwrap_or(0)
+}
@@ -1,159 +1,615 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
-use std::error::Error as _;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use colored::Colorize;
 use ignore::gitignore::Gitignore;
-use reqwest::{Client, IntoUrl, Proxy, Url};
+use once_cell::sync::OnceCell;
+use reqwest::{Client, IntoUrl, Proxy, RequestBuilder, StatusCode, Url, cookie::{CookieStore, Jar}, header::RANGE};
 use scraper::{ElementRef, Html, Selector};
 use serde_json::json;
+use thiserror::Error;
+use tokio::sync::Mutex;
 
-use crate::{ILIAS_URL, cli::Opt, get_request_ticket, selectors::*};
+use crate::{bandwidth::Throttle, instance::{self, ilias_url, LoginMethod}, cli::{ask_user_pass, Opt}, retry, ratelimit::{RateLimiter, RequestKind}, selectors::*, shutdown, state::SyncState, totp, util::{parse_date, parse_size}};
 
 pub struct ILIAS {
 	pub opt: Opt,
 	pub ignore: Gitignore,
-	// TODO: use these for re-authentication in case of session timeout/invalidation
-	user: String,
-	pass: String,
+	pub state: SyncState,
+	/// Username and password, asked for when logging in for the first time (see [`ask_user_pass`])
+	credentials: OnceCell<(String, String)>,
 	client: Client,
+	cookies: Arc<Jar>,
+	/// Incremented after every successful login
+	session: AtomicUsize,
+	relogin_lock: Mutex<()>,
+	limiter: RateLimiter,
+	/// Limits the total download speed
+	pub bandwidth: Throttle,
+	/// Personal desktop loaded while checking the saved session, used by [`ILIAS::personal_desktop`]
+	desktop_page: std::sync::Mutex<Option<String>>,
 }
 
-/// Returns true if the error is caused by:
-/// "http2 error: protocol error: not a result of an error"
-fn error_is_http2(error: &reqwest::Error) -> bool {
-	error.source() // hyper::Error
-		.map(|x| x.source()) // -> h2::Error
-		.flatten()
-		.map(|x| x.downcast_ref::<h2::Error>())
-		.flatten()
-		.map(|x| x.reason())
-		.flatten()
-		.map(|x| x == h2::Reason::NO_ERROR)
-		.unwrap_or(false)
+/// Maximum number of re-logins performed during a single run
+const MAX_RELOGINS: usize = 5;
+
+
+/// Errors that occur while talking to ILIAS
+#[derive(Debug, Error)]
+pub enum IliasError {
+	/// Login was rejected, e.g. because of an incorrect password (the callers add "login failed" context)
+	#[error("{0}")]
+	Auth(&'static str),
+	/// Session expired and could not be renewed
+	#[error("session expired: {0}")]
+	SessionExpired(String),
+	/// The credentials could not be read (e.g. the password command failed)
+	#[error("credentials input failed: {0}")]
+	Credentials(String),
+	/// Logging in again after the session expired failed
+	#[error("re-login failed")]
+	Relogin(#[source] Box<IliasError>),
+	/// ILIAS displayed an error message instead of the requested page
+	#[error("ILIAS error: {0}")]
+	ErrorPage(String),
+	/// An element was not found on the page (named after the selector), ILIAS probably changed its HTML
+	#[error("unexpected page structure: {0} not found")]
+	PageStructure(&'static str),
+	#[error("HTTP status {status} for {url}")]
+	Status { status: StatusCode, url: String },
+	/// No data received within the stall timeout
+	#[error("no data received for {0:?}")]
+	Stalled(Duration),
+	/// Request or download cancelled because the run was interrupted
+	#[error("interrupted")]
+	Interrupted,
+	#[error(transparent)]
+	Http(#[from] reqwest::Error),
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+impl IliasError {
+	/// URL of the failed request (if known)
+	pub fn url(&self) -> Option<&Url> {
+		match self {
+			IliasError::Http(e) => e.url(),
+			_ => None,
+		}
+	}
+}
+
+/// Add the cookies stored in Netscape cookies.txt format to the cookie jar.
+fn load_cookies(jar: &Jar, text: &str) -> Result<()> {
+	for line in text.lines() {
+		// curl marks HttpOnly cookies using this prefix
+		let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+		if line.trim().is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let fields = line.split('\t').collect::<Vec<_>>();
+		if fields.len() != 7 {
+			return Err(anyhow!("invalid cookie line: {:?}", line));
+		}
+		let (domain, subdomains, path, secure, name, value) = (fields[0], fields[1], fields[2], fields[3], fields[5], fields[6]);
+		let url = Url::parse(&format!("https://{}{}", domain.trim_start_matches('.'), path))?;
+		let mut cookie = format!("{}={}; Path={}", name, value, path);
+		if subdomains == "TRUE" {
+			cookie += "; Domain=";
+			cookie += domain;
+		}
+		if secure == "TRUE" {
+			cookie += "; Secure";
+		}
+		jar.add_cookie_str(&cookie, &url);
+	}
+	Ok(())
+}
+
+/// Serialize the cookies sent to the specified URL in Netscape cookies.txt format.
+fn save_cookies(jar: &Jar, url: &Url) -> String {
+	let mut text = "# Netscape HTTP Cookie File\n".to_owned();
+	let domain = url.host_str().unwrap_or_default();
+	if let Some(cookies) = jar.cookies(url).as_ref().and_then(|x| x.to_str().ok()) {
+		for cookie in cookies.split("; ") {
+			if let Some((name, value)) = cookie.split_once('=') {
+				text += &format!("{}\tFALSE\t/\tTRUE\t0\t{}\t{}\n", domain, name, value);
+			}
+		}
+	}
+	text
+}
+
+/// Returns true if the request was redirected to a login page.
+fn is_login_redirect(url: &Url) -> bool {
+	// path relative to the ILIAS installation, which is not necessarily located at the root of the host
+	let ilias_path = url.as_str().strip_prefix(ilias_url()).map(|x| x.split(&['?', '#'][..]).next().unwrap_or_default());
+	url.path().starts_with("/Shibboleth.sso/")
+		|| (url.host_str().is_some() && url.host_str() == instance::current().idp_host().as_deref())
+		|| ilias_path
+			.map(|path| {
+				path == "login.php"
+					|| path.starts_with("Shibboleth.sso/")
+					|| url.query_pairs().any(|(k, v)| k == "baseClass" && v.eq_ignore_ascii_case("ilStartUpGUI"))
+			})
+			.unwrap_or(false)
+}
+
+/// Returns true if the page is a login form instead of the requested content.
+fn is_login_page(html: &Html) -> bool {
+	html.select(&saml_response).next().is_some() || html.select(&login_form).next().is_some()
+}
+
+/// Names and values of the input fields of the form (including hidden fields and submit buttons).
+fn form_fields(form: ElementRef) -> serde_json::Map<String, serde_json::Value> {
+	let input = Selector::parse("input").unwrap();
+	form.select(&input)
+		.filter_map(|x| Some((x.value().attr("name")?.to_owned(), json!(x.value().attr("value").unwrap_or_default()))))
+		.collect()
+}
+
+/// Find the form asking for a one-time code (second factor) on the identity provider page.
+/// Returns the URL to submit the form to and its other fields.
+fn otp_form(url: &Url, text: &str, field: &str) -> Option<(Url, serde_json::Map<String, serde_json::Value>)> {
+	let dom = Html::parse_document(text);
+	let form = Selector::parse("form").unwrap();
+	let form = dom.select(&form).find(|form| {
+		form.select(&Selector::parse("input").unwrap())
+			.any(|x| x.value().attr("name") == Some(field))
+	})?;
+	let action = url.join(form.value().attr("action").unwrap_or_default()).ok()?;
+	let mut fields = form_fields(form);
+	fields.remove(field);
+	Some((action, fields))
 }
 
 impl ILIAS {
-	pub async fn login(opt: Opt, user: impl Into<String>, pass: impl Into<String>, ignore: Gitignore) -> Result<Self> {
-		let user = user.into();
-		let pass = pass.into();
+	pub async fn login(opt: Opt, ignore: Gitignore, state: SyncState) -> Result<Self> {
+		let cookies = Arc::new(Jar::default());
+		let cookie_file = ILIAS::cookie_file(&opt);
+		let saved_session = if opt.browser_session() {
+			if let Some(path) = opt.cookies_file.as_ref() {
+				let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+				load_cookies(&cookies, &text).context("invalid cookies file")?;
+			}
+			let url = Url::parse(ilias_url())?;
+			for cookie in &opt.session_cookie {
+				cookies.add_cookie_str(cookie, &url);
+			}
+			true
+		} else if let Ok(text) = std::fs::read_to_string(&cookie_file) {
+			match load_cookies(&cookies, &text) {
+				Ok(()) => true,
+				Err(e) => {
+					warning!(".iliascookies could not be loaded:", e);
+					false
+				},
+			}
+		} else {
+			false
+		};
 		let mut builder = Client::builder()
-			.cookie_store(true)
+			.cookie_provider(Arc::clone(&cookies))
 			.user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")));
 		if let Some(proxy) = opt.proxy.as_ref() {
 			let proxy = Proxy::all(proxy)?;
 			builder = builder.proxy(proxy);
 		}
 		let client = builder
-			// timeout is infinite by default
+			.connect_timeout(Duration::from_secs(opt.connect_timeout))
 			.build()?;
-		let this = ILIAS { opt, ignore, user, pass, client };
-		info!("Logging into ILIAS using KIT account..");
-		let session_establishment = this.client
-			.post("https://ilias.studium.kit.edu/Shibboleth.sso/Login")
+		let limiter = RateLimiter::new(&opt, &Url::parse(ilias_url())?);
+		let bandwidth = Throttle::new(opt.max_bandwidth.clone());
+		let this = ILIAS {
+			opt,
+			ignore,
+			state,
+			credentials: OnceCell::new(),
+			client,
+			cookies,
+			session: AtomicUsize::new(0),
+			relogin_lock: Mutex::new(()),
+			limiter,
+			bandwidth,
+			desktop_page: std::sync::Mutex::new(None),
+		};
+		if this.opt.browser_session() {
+			if !this.is_session_valid().await {
+				return Err(IliasError::SessionExpired("browser session is invalid or expired, log in again and export a new cookie".to_owned()).into());
+			}
+			this.session.fetch_add(1, Ordering::SeqCst);
+			if let Err(e) = this.save_session() {
+				warning!("could not save session cookies:", e);
+			}
+			success!("Using browser session");
+			return Ok(this);
+		}
+		if saved_session {
+			if this.is_session_valid().await {
+				this.session.fetch_add(1, Ordering::SeqCst);
+				success!("Reusing previous session");
+				return Ok(this);
+			}
+			log!(1, "Saved session expired");
+		}
+		this.authenticate().await?;
+		success!("Logged in!");
+		Ok(this)
+	}
+
+	fn cookie_file(opt: &Opt) -> PathBuf {
+		opt.output.join(".iliascookies")
+	}
+
+	/// Check whether the current cookies belong to a valid session.
+	/// The personal desktop page is kept for [`ILIAS::personal_desktop`].
+	async fn is_session_valid(&self) -> bool {
+		let url = instance::current().personal_desktop_url();
+		self.limiter.bucket(&url, RequestKind::Page).acquire().await;
+		let response = match self.client.get(url).timeout(self.request_timeout()).send().await {
+			Ok(response) if response.status().is_success() && !is_login_redirect(response.url()) => response,
+			_ => return false,
+		};
+		let text = match response.text().await {
+			Ok(text) => text,
+			Err(_) => return false,
+		};
+		if is_login_page(&Html::parse_document(&text)) {
+			return false;
+		}
+		*self.desktop_page.lock().unwrap() = Some(text);
+		true
+	}
+
+	/// Username and password, asked for (or read from the configured sources) on first use.
+	async fn credentials(&self) -> Result<(String, String), IliasError> {
+		if let Some(credentials) = self.credentials.get() {
+			return Ok(credentials.clone());
+		}
+		let opt = self.opt.clone();
+		// may prompt for the credentials, or run the password command
+		let credentials = tokio::task::spawn_blocking(move || ask_user_pass(&opt))
+			.await
+			.map_err(|_| IliasError::Auth("reading the credentials failed"))?
+			.map_err(|e| IliasError::Credentials(format!("{:#}", e)))?;
+		Ok(self.credentials.get_or_init(|| credentials).clone())
+	}
+
+	/// Store the session cookies in the output directory, so the next run can skip the login.
+	/// Nothing is written in a dry run.
+	fn save_session(&self) -> Result<()> {
+		if self.opt.dry_run {
+			return Ok(());
+		}
+		let path = ILIAS::cookie_file(&self.opt);
+		std::fs::write(&path, save_cookies(&self.cookies, &Url::parse(ilias_url())?))?;
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+		}
+		Ok(())
+	}
+
+	/// Log in using the stored credentials and the login method of the instance.
+	async fn authenticate(&self) -> Result<(), IliasError> {
+		if self.opt.browser_session() {
+			return Err(IliasError::SessionExpired("browser session expired, log in again and export a new cookie".to_owned()));
+		}
+		match instance::current().login_method {
+			LoginMethod::Shibboleth => self.shibboleth_login().await?,
+			LoginMethod::Local => self.local_login().await?,
+		}
+		self.session.fetch_add(1, Ordering::SeqCst);
+		if let Err(e) = self.save_session() {
+			warning!("could not save session cookies:", e);
+		}
+		Ok(())
+	}
+
+	/// Perform the Shibboleth login flow using the identity provider of the instance.
+	async fn shibboleth_login(&self) -> Result<(), IliasError> {
+		let instance = instance::current();
+		let (user, pass) = self.credentials().await?;
+		log!(0, "Logging into ILIAS ({}) using Shibboleth..", instance.name);
+		let base_path = Url::parse(&instance.url).map(|x| x.path().to_owned()).unwrap_or_else(|_| "/".to_owned());
+		let session_establishment = self.client
+			.post(format!("{}Shibboleth.sso/Login", instance.url))
 			.form(&json!({
 				"sendLogin": "1",
-				"idp_selection": "https://idp.scc.kit.edu/idp/shibboleth",
-				"target": "/shib_login.php?target=",
-				"home_organization_selection": "Mit KIT-Account anmelden"
+				"idp_selection": &instance.idp_entity_id,
+				"target": format!("{}shib_login.php?target=", base_path),
+				"home_organization_selection": &instance.idp_selection_label
 			}))
+			.timeout(self.request_timeout())
 			.send().await?;
 		let url = session_establishment.url().clone();
 		let text = session_establishment.text().await?;
-		let dom_sso = Html::parse_document(text.as_str());
-		let csrf_token = dom_sso
-			.select(&Selector::parse(r#"input[name="csrf_token"]"#).unwrap())
-			.next().context("no csrf token")?;
-		info!("Logging into Shibboleth..");
-		let login_response = this.client
+		let csrf_token = {
+			let dom_sso = Html::parse_document(text.as_str());
+			let csrf_token = dom_sso
+				.select(&Selector::parse(r#"input[name="csrf_token"]"#).unwrap())
+				.next().ok_or(IliasError::PageStructure("csrf_token"))?;
+			csrf_token.value().attr("value").ok_or(IliasError::PageStructure("csrf_token value"))?.to_owned()
+		};
+		info!("Logging into identity provider..");
+		let mut form = serde_json::Map::new();
+		form.insert(instance.username_field.clone(), json!(&user));
+		form.insert(instance.password_field.clone(), json!(&pass));
+		form.insert("_eventId_proceed".to_owned(), json!(""));
+		form.insert("csrf_token".to_owned(), json!(csrf_token));
+		let login_response = self.client
 			.post(url)
-			.form(&json!({
-				"j_username": &this.user,
-				"j_password": &this.pass,
-				"_eventId_proceed": "",
-				"csrf_token": csrf_token.value().attr("value").context("no csrf token")?,
-			}))
-			.send().await?
-			.text().await?;
-		let dom = Html::parse_document(&login_response);
-		let saml = Selector::parse(r#"input[name="SAMLResponse"]"#).unwrap();
-		let saml = dom
-			.select(&saml)
-			.next().context("no SAML response, incorrect password?")?;
-		let relay_state = Selector::parse(r#"input[name="RelayState"]"#).unwrap();
-		let relay_state = dom.select(&relay_state).next().context("no relay state")?;
+			.form(&form)
+			.timeout(self.request_timeout())
+			.send().await?;
+		let url = login_response.url().clone();
+		let mut login_response = login_response.text().await?;
+		if let Some((action, mut form)) = otp_form(&url, &login_response, &instance.otp_field) {
+			info!("Two-factor authentication required");
+			form.insert(instance.otp_field.clone(), json!(self.otp_code().await?));
+			form.entry("_eventId_proceed").or_insert_with(|| json!(""));
+			login_response = self.client
+				.post(action)
+				.form(&form)
+				.timeout(self.request_timeout())
+				.send().await?
+				.text().await?;
+			if otp_form(&url, &login_response, &instance.otp_field).is_some() {
+				return Err(IliasError::Auth("one-time code was not accepted"));
+			}
+		}
+		let (saml, relay_state) = {
+			let dom = Html::parse_document(&login_response);
+			let saml = Selector::parse(r#"input[name="SAMLResponse"]"#).unwrap();
+			let saml = dom
+				.select(&saml)
+				.next().ok_or(IliasError::Auth("no SAML response, incorrect password?"))?;
+			let relay_state = Selector::parse(r#"input[name="RelayState"]"#).unwrap();
+			let relay_state = dom.select(&relay_state).next().ok_or(IliasError::PageStructure("RelayState"))?;
+			(
+				saml.value().attr("value").ok_or(IliasError::PageStructure("SAMLResponse value"))?.to_owned(),
+				relay_state.value().attr("value").ok_or(IliasError::PageStructure("RelayState value"))?.to_owned(),
+			)
+		};
 		info!("Logging into ILIAS..");
-		this.client
-			.post("https://ilias.studium.kit.edu/Shibboleth.sso/SAML2/POST")
+		self.client
+			.post(format!("{}Shibboleth.sso/SAML2/POST", instance.url))
 			.form(&json!({
-				"SAMLResponse": saml.value().attr("value").context("no SAML value")?,
-				"RelayState": relay_state.value().attr("value").context("no RelayState value")?
+				"SAMLResponse": saml,
+				"RelayState": relay_state
 			}))
+			.timeout(self.request_timeout())
 			.send().await?;
+		Ok(())
+	}
+
+	/// One-time code for two-factor authentication: computed from the TOTP secret (if specified) or entered by the user.
+	async fn otp_code(&self) -> Result<String, IliasError> {
+		if let Some(secret) = self.opt.totp_secret.as_ref() {
+			return totp::code(secret).map_err(|_| IliasError::Auth("invalid TOTP secret"));
+		}
+		if !atty::is(atty::Stream::Stdin) {
+			return Err(IliasError::Auth("one-time code required, use --totp-secret for unattended runs"));
+		}
+		// the prompt blocks until the user answers, which may happen mid-run when logging in again
+		let code = tokio::task::spawn_blocking(|| rprompt::prompt_reply_stdout("One-time code: "))
+			.await
+			.map_err(|_| IliasError::Auth("reading the one-time code failed"))??;
+		Ok(code.trim().to_owned())
+	}
+
+	/// Submit the login form of ILIAS (ilStartUpGUI).
+	async fn local_login(&self) -> Result<(), IliasError> {
+		let instance = instance::current();
+		let (user, pass) = self.credentials().await?;
+		log!(0, "Logging into ILIAS ({})..", instance.name);
+		let login_page = self.client
+			.get(format!("{}login.php?cmd=force_login", instance.url))
+			.timeout(self.request_timeout())
+			.send().await?;
+		let url = login_page.url().clone();
+		let text = login_page.text().await?;
+		let (action, mut form) = {
+			let dom = Html::parse_document(&text);
+			let element = dom.select(&login_form).next().ok_or(IliasError::PageStructure("login form"))?;
+			let action = element.value().attr("action").ok_or(IliasError::PageStructure("login form action"))?;
+			let action = url.join(action).map_err(|_| IliasError::PageStructure("login form action"))?;
+			// hidden fields and the submit button (cmd[doStandardAuthentication])
+			(action, form_fields(element))
+		};
+		form.insert("username".to_owned(), json!(&user));
+		form.insert("password".to_owned(), json!(&pass));
+		let response = self.client
+			.post(action)
+			.form(&form)
+			.timeout(self.request_timeout())
+			.send().await?;
+		let redirected = is_login_redirect(response.url());
+		let text = response.text().await?;
+		if redirected || is_login_page(&Html::parse_document(&text)) {
+			return Err(IliasError::Auth("login form shown again, incorrect password?"));
+		}
+		Ok(())
+	}
+
+	/// Log in again after the session expired.
+	/// `session` is the session counter observed before the failed request:
+	/// if another task already logged in again in the meantime, nothing is done.
+	async fn reauthenticate(&self, session: usize) -> Result<(), IliasError> {
+		let _guard = self.relogin_lock.lock().await;
+		let current = self.session.load(Ordering::SeqCst);
+		if current != session {
+			return Ok(());
+		}
+		if current > MAX_RELOGINS {
+			return Err(IliasError::SessionExpired(format!("giving up after {} re-logins", MAX_RELOGINS)));
+		}
+		warning!(format => "ILIAS session expired, logging in again..");
+		self.authenticate().await.map_err(|e| IliasError::Relogin(Box::new(e)))?;
 		success!("Logged in!");
-		Ok(this)
+		Ok(())
+	}
+
+	/// Switch the view mode of the repository ("tree" is required for the content tree, "flat" loads faster).
+	/// Unlike other requests, this is also sent after the run was interrupted (to restore the setting).
+	pub async fn set_view_mode(&self, mode: &str) -> Result<(), IliasError> {
+		let url = format!("{}ilias.php?baseClass=ilRepositoryGUI&cmd=frameset&set_mode={}&ref_id=1", ilias_url(), mode);
+		self.limiter.bucket(&url, RequestKind::Page).acquire().await;
+		self.client.get(url).timeout(self.request_timeout()).send().await?.error_for_status()?;
+		Ok(())
+	}
+
+	/// Load a page or small file, limited by the request timeout.
+	pub async fn download(&self, url: &str) -> Result<reqwest::Response, IliasError> {
+		self.get(url, 0, RequestKind::Page).await
+	}
+
+	/// Download a (large) file starting at the specified byte offset (using a Range request).
+	/// There is no total timeout, the body should be read using [`ILIAS::stall_timeout`].
+	pub async fn download_from(&self, url: &str, offset: u64) -> Result<reqwest::Response, IliasError> {
+		self.get(url, offset, RequestKind::Download).await
+	}
+
+	/// Total timeout of page requests (including the login)
+	fn request_timeout(&self) -> Duration {
+		Duration::from_secs(self.opt.request_timeout)
+	}
+
+	/// Abort streamed downloads that receive no data for this duration
+	pub fn stall_timeout(&self) -> Duration {
+		Duration::from_secs(self.opt.stall_timeout)
 	}
 
-	pub async fn download(&self, url: &str) -> Result<reqwest::Response> {
-		get_request_ticket().await;
+	/// Error statuses (except 416 Range Not Satisfiable) are returned as [`IliasError::Status`].
+	async fn get(&self, url: &str, offset: u64, kind: RequestKind) -> Result<reqwest::Response, IliasError> {
 		log!(2, "Downloading {}", url);
 		let url = if url.starts_with("http://") || url.starts_with("https://") {
 			url.to_owned()
-		} else if url.starts_with("ilias.studium.kit.edu") {
+		} else if instance::current().host().map(|host| url.starts_with(&host)).unwrap_or(false) {
 			format!("https://{}", url)
 		} else {
-			format!("{}{}", ILIAS_URL, url)
+			format!("{}{}", ilias_url(), url)
 		};
-		for attempt in 1..10 {
-			let result = self.client.get(url.clone()).send().await;
+		let mut relogged_in = false;
+		loop {
+			let session = self.session.load(Ordering::SeqCst);
+			let result = self.send(&url, kind, || {
+				let request = self.client.get(url.clone());
+				if offset > 0 {
+					request.header(RANGE, format!("bytes={}-", offset))
+				} else {
+					request
+				}
+			}).await;
 			match result {
-				Ok(x) => return Ok(x),
-				Err(e) if attempt <= 3 && error_is_http2(&e) => {
-					warning!(1; "encountered HTTP/2 NO_ERROR, retrying download..");
-					continue
+				Ok(x) if is_login_redirect(x.url()) => {
+					if relogged_in {
+						return Err(IliasError::SessionExpired(format!("redirected to login page after re-login: {}", x.url())));
+					}
+					self.reauthenticate(session).await?;
+					relogged_in = true;
 				},
-				Err(e) => return Err(e.into())
+				Ok(x) if (x.status().is_client_error() || x.status().is_server_error())
+					&& x.status() != StatusCode::RANGE_NOT_SATISFIABLE =>
+				{
+					return Err(IliasError::Status { status: x.status(), url: x.url().to_string() });
+				},
+				Ok(x) => return Ok(x),
+				Err(e) => return Err(e)
 			}
 		}
-		unreachable!()
 	}
 
-	pub async fn head<U: IntoUrl>(&self, url: U) -> Result<reqwest::Response, reqwest::Error> {
-		get_request_ticket().await;
+	pub async fn head<U: IntoUrl>(&self, url: U) -> Result<reqwest::Response, IliasError> {
 		let url = url.into_url()?;
-		for attempt in 1..10 {
-			let result = self.client.head(url.clone()).send().await;
-			match result {
-				Ok(x) => return Ok(x),
-				Err(e) if attempt <= 3 && error_is_http2(&e) => {
-					warning!(1; "encountered HTTP/2 NO_ERROR, retrying HEAD request..");
-					continue
-				},
-				Err(e) => return Err(e)
+		self.send(url.as_str(), RequestKind::Page, || self.client.head(url.clone())).await
+	}
+
+	/// Send the request, retrying transient failures (see [`retry::retry_reason`]) with exponential backoff.
+	/// Page requests are limited by the request timeout,
+	/// streamed downloads by the stall timeout (until the response headers are received).
+	async fn send(&self, url: &str, kind: RequestKind, request: impl Fn() -> RequestBuilder) -> Result<reqwest::Response, IliasError> {
+		let bucket = self.limiter.bucket(url, kind);
+		let mut attempt = 1;
+		loop {
+			if shutdown::requested() {
+				return Err(IliasError::Interrupted);
+			}
+			tokio::select! {
+				_ = bucket.acquire() => {},
+				_ = shutdown::wait() => return Err(IliasError::Interrupted),
+			}
+			let result = if kind == RequestKind::Download {
+				match tokio::time::timeout(self.stall_timeout(), request().send()).await {
+					Ok(result) => result,
+					Err(_) if attempt < self.opt.max_attempts && !shutdown::requested() => {
+						let delay = retry::backoff(attempt as u32);
+						warning!(format => "{}: no response, retrying in {:.1}s (attempt {}/{})", url, delay.as_secs_f64(), attempt + 1, self.opt.max_attempts);
+						tokio::select! {
+							_ = tokio::time::sleep(delay) => {},
+							_ = shutdown::wait() => return Err(IliasError::Interrupted),
+						}
+						attempt += 1;
+						continue;
+					},
+					Err(_) => return Err(IliasError::Stalled(self.stall_timeout())),
+				}
+			} else {
+				request().timeout(self.request_timeout()).send().await
+			};
+			match result.as_ref().map(|x| x.status()) {
+				Ok(StatusCode::TOO_MANY_REQUESTS) | Ok(StatusCode::SERVICE_UNAVAILABLE) => bucket.slow_down(),
+				Ok(_) => bucket.speed_up(),
+				Err(_) => {},
 			}
+			let reason = match retry::retry_reason(&result) {
+				Some(reason) if attempt < self.opt.max_attempts && !shutdown::requested() => reason,
+				_ => return Ok(result?),
+			};
+			let delay = retry::retry_delay(attempt as u32, &result);
+			warning!(format => "{}: {}, retrying in {:.1}s (attempt {}/{})", url, reason, delay.as_secs_f64(), attempt + 1, self.opt.max_attempts);
+			tokio::select! {
+				_ = tokio::time::sleep(delay) => {},
+				_ = shutdown::wait() => return Err(IliasError::Interrupted),
+			}
+			attempt += 1;
 		}
-		unreachable!()
 	}
 
-	pub async fn get_html(&self, url: &str) -> Result<Html> {
-		let text = self.download(url).await?.text().await?;
-		let html = Html::parse_document(&text);
-		if html.select(&alert_danger).next().is_some() {
-			Err(anyhow!("ILIAS error"))
-		} else {
-			Ok(html)
-		}
+	pub async fn get_html(&self, url: &str) -> Result<Html, IliasError> {
+		self.get_html_checked(url, Html::parse_document).await
 	}
 
-	pub async fn get_html_fragment(&self, url: &str) -> Result<Html> {
-		let text = self.download(url).await?.text().await?;
-		let html = Html::parse_fragment(&text);
-		if html.select(&alert_danger).next().is_some() {
-			Err(anyhow!("ILIAS error"))
-		} else {
-			Ok(html)
+	pub async fn get_html_fragment(&self, url: &str) -> Result<Html, IliasError> {
+		self.get_html_checked(url, Html::parse_fragment).await
+	}
+
+	/// Download and parse a page, logging in again if ILIAS returns a login form.
+	async fn get_html_checked(&self, url: &str, parse: fn(&str) -> Html) -> Result<Html, IliasError> {
+		for attempt in 1..=2 {
+			let session = self.session.load(Ordering::SeqCst);
+			let text = self.download(url).await?.text().await?;
+			{
+				let html = parse(&text);
+				if let Some(alert) = html.select(&alert_danger).next() {
+					return Err(IliasError::ErrorPage(alert.text().collect::<String>().trim().to_owned()));
+				}
+				if !is_login_page(&html) {
+					return Ok(html);
+				}
+			}
+			if attempt == 2 {
+				return Err(IliasError::SessionExpired(format!("received login page after re-login: {}", url)));
+			}
+			self.reauthenticate(session).await?;
 		}
+		unreachable!()
 	}
 
 	pub fn get_items(html: &Html) -> Vec<Result<Object>> {
@@ -187,7 +643,11 @@ impl ILIAS {
 	}
 
 	pub async fn personal_desktop(&self) -> Result<Dashboard> {
-		let html = self.get_html("https://ilias.studium.kit.edu/ilias.php?baseClass=ilPersonalDesktopGUI&cmd=jumpToSelectedItems").await?;
+		let page = self.desktop_page.lock().unwrap().take();
+		let html = match page {
+			Some(text) => Html::parse_document(&text),
+			None => self.get_html(&instance::current().personal_desktop_url()).await?,
+		};
 		let items = ILIAS::get_items(&html)
 			.into_iter()
 			.flat_map(Result::ok)
@@ -200,7 +660,7 @@ impl ILIAS {
 		// opening the same url in browser does show sub-folders?!
 		let url = format!(
 			"{}ilias.php?ref_id={}&cmdClass=ilobjcoursegui&cmd=showRepTree&cmdNode={}&baseClass=ilRepositoryGUI&cmdMode=asynch&exp_cmd=getNodeAsync&node_id=exp_node_rep_exp_{}&exp_cont=il_expl2_jstree_cont_rep_exp&searchterm=",
-			ILIAS_URL, ref_id, cmd_node, ref_id
+			ilias_url(), ref_id, cmd_node, ref_id
 		);
 		let html = self.get_html_fragment(&url).await?;
 		let mut items = Vec::new();
@@ -218,11 +678,21 @@ pub struct Dashboard {
 	pub items: Vec<Object>,
 }
 
+/// Metadata shown next to files in the ILIAS item list / video table
+#[derive(Debug, Default)]
+pub struct FileMeta {
+	pub version: Option<u32>,
+	/// Upload / last change date, as displayed by ILIAS
+	pub date: Option<String>,
+	/// Approximate file size in bytes
+	pub size: Option<u64>,
+}
+
 #[derive(Debug)]
 pub enum Object {
 	Course { name: String, url: URL },
 	Folder { name: String, url: URL },
-	File { name: String, url: URL },
+	File { name: String, url: URL, meta: FileMeta },
 	Forum { name: String, url: URL },
 	Thread { url: URL },
 	Wiki { name: String, url: URL },
@@ -231,7 +701,7 @@ pub enum Object {
 	Survey { name: String, url: URL },
 	Presentation { name: String, url: URL },
 	PluginDispatch { name: String, url: URL },
-	Video { url: URL },
+	Video { url: URL, meta: FileMeta },
 	Generic { name: String, url: URL },
 }
 
@@ -252,7 +722,7 @@ impl Object {
 			| PluginDispatch { name, .. }
 			| Generic { name, .. } => &name,
 			Thread { url } => &url.thr_pk.as_ref().unwrap(),
-			Video { url } => &url.url,
+			Video { url, .. } => &url.url,
 		}
 	}
 
@@ -269,7 +739,7 @@ impl Object {
 			| Presentation { url, .. }
 			| ExerciseHandler { url, .. }
 			| PluginDispatch { url, .. }
-			| Video { url }
+			| Video { url, .. }
 			| Generic { url, .. } => &url,
 		}
 	}
@@ -292,6 +762,25 @@ impl Object {
 		}
 	}
 
+	/// Identifier that stays the same if the object is renamed or moved.
+	pub fn id(&self) -> String {
+		let url = self.url();
+		if let Some(thr_pk) = url.thr_pk.as_ref() {
+			return format!("thr_{}", thr_pk);
+		}
+		match self {
+			// exercise files
+			File { .. } if url.file.is_some() => return format!("ref_{}_file_{}", url.ref_id, url.file.as_ref().unwrap()),
+			File { .. } | Video { .. } | Generic { .. } => {},
+			_ if !url.ref_id.is_empty() => return format!("ref_{}", url.ref_id),
+			_ => {},
+		}
+		if let Some(target) = url.target.as_ref() {
+			return target.trim_end_matches("_download").to_owned();
+		}
+		url.url.clone()
+	}
+
 	pub fn is_dir(&self) -> bool {
 		match self {
 			Course { .. }
@@ -312,18 +801,18 @@ impl Object {
 			.replace('/', "-")
 			.trim()
 			.to_owned();
-		let url = URL::from_href(link.value().attr("href").context("link missing href")?)?;
+		let url = URL::from_href(link.value().attr("href").ok_or(IliasError::PageStructure("link href"))?)?;
 		Object::from_url(url, name, Some(item))
 	}
 
-	pub fn from_url(mut url: URL, mut name: String, item: Option<ElementRef>) -> Result<Self> {
+	pub fn from_url(mut url: URL, name: String, item: Option<ElementRef>) -> Result<Self> {
 		if url.thr_pk.is_some() {
 			return Ok(Thread { url });
 		}
 
 		if url
 			.url
-			.starts_with("https://ilias.studium.kit.edu/goto.php")
+			.starts_with(&format!("{}goto.php", ilias_url()))
 		{
 			let target = url.target.as_deref().unwrap_or("NONE");
 			if target.starts_with("wiki_") {
@@ -362,20 +851,25 @@ impl Object {
 					return Ok(Generic { name, url });
 				} else {
 					let mut item_props = item.context("can't construct file object without HTML object")?.select(&item_prop);
-					let ext = item_props.next().context("cannot find file extension")?;
-					let version = item_props
-						.nth(1)
-						.context("cannot find 3rd file metadata")?
-						.text()
-						.collect::<String>();
-					let version = version.trim();
-					if let Some(v) = version.strip_prefix("Version: ") {
-						name += "_v";
-						name += v;
+					let ext = item_props.next().ok_or(IliasError::PageStructure("item_prop"))?;
+					let mut meta = FileMeta::default();
+					for prop in item_props {
+						let prop = prop.text().collect::<String>();
+						let prop = prop.trim();
+						if let Some(v) = prop.strip_prefix("Version: ") {
+							meta.version = v.parse().ok();
+						} else if parse_date(prop).is_some() {
+							meta.date = Some(prop.to_owned());
+						} else if let Some(size) = parse_size(prop) {
+							meta.size = Some(size);
+						}
 					}
+					let ref_id = url.target.as_ref().unwrap().split('_').nth(1).unwrap();
+					url.ref_id = ref_id.to_owned();
 					return Ok(File {
 						name: format!("{}.{}", name, ext.text().collect::<String>().trim()),
 						url,
+						meta,
 					});
 				}
 			}
@@ -439,8 +933,8 @@ impl URL {
 	}
 
 	pub fn from_href(href: &str) -> Result<Self> {
-		let url = if !href.starts_with(ILIAS_URL) {
-			Url::parse(&format!("{}{}", ILIAS_URL, href))?
+		let url = if !href.starts_with(ilias_url()) {
+			Url::parse(&format!("{}{}", ilias_url(), href))?
 		} else {
 			Url::parse(href)?
 		};
@@ -484,3 +978,52 @@ impl URL {
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cookies(jar: &Jar, url: &str) -> String {
+		jar.cookies(&Url::parse(url).unwrap())
+			.map(|x| x.to_str().unwrap().to_owned())
+			.unwrap_or_default()
+	}
+
+	#[test]
+	fn load_cookies_txt() {
+		let jar = Jar::default();
+		let text = "# Netscape HTTP Cookie File\n\
+			\n\
+			ilias.studium.kit.edu\tFALSE\t/\tTRUE\t0\tPHPSESSID\tabc123\n\
+			#HttpOnly_ilias.studium.kit.edu\tFALSE\t/\tTRUE\t0\tilClientId\tproduktiv\n\
+			.kit.edu\tTRUE\t/\tFALSE\t0\tlang\tde\n";
+		load_cookies(&jar, text).unwrap();
+		let sent = cookies(&jar, "https://ilias.studium.kit.edu/ilias.php");
+		assert!(sent.contains("PHPSESSID=abc123"), "{}", sent);
+		assert!(sent.contains("ilClientId=produktiv"), "{}", sent);
+		assert!(sent.contains("lang=de"), "{}", sent);
+		// secure cookies are only sent over HTTPS, domain cookies to all subdomains
+		assert_eq!(cookies(&jar, "http://ilias.studium.kit.edu/"), "lang=de");
+		assert_eq!(cookies(&jar, "https://www.kit.edu/"), "lang=de");
+	}
+
+	#[test]
+	fn load_cookies_invalid() {
+		let jar = Jar::default();
+		assert!(load_cookies(&jar, "ilias.studium.kit.edu\tFALSE\t/\tTRUE\t0\tPHPSESSID\n").is_err());
+		assert!(load_cookies(&jar, "PHPSESSID=abc123\n").is_err());
+		// comments only
+		assert!(load_cookies(&jar, "# Netscape HTTP Cookie File\n# ilias.studium.kit.edu\n").is_ok());
+	}
+
+	#[test]
+	fn saved_cookies_roundtrip() {
+		let url = Url::parse("https://ilias.studium.kit.edu/").unwrap();
+		let jar = Jar::default();
+		jar.add_cookie_str("PHPSESSID=abc123; Path=/", &url);
+		let text = save_cookies(&jar, &url);
+		let loaded = Jar::default();
+		load_cookies(&loaded, &text).unwrap();
+		assert_eq!(cookies(&loaded, url.as_str()), "PHPSESSID=abc123");
+	}
+}
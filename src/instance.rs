@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use url::Url;
+
+use crate::cli::Opt;
+
+/// Settings of an ILIAS installation, selected using `--instance`.
+/// Additional instances can be defined in the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Instance {
+	pub name: String,
+	/// Base URL of the ILIAS installation, e.g. https://ilias.studium.kit.edu/
+	pub url: String,
+	#[serde(default)]
+	pub login_method: LoginMethod,
+	/// Entity ID of the Shibboleth identity provider
+	#[serde(default)]
+	pub idp_entity_id: String,
+	/// Label of the organization selected on the ILIAS Shibboleth login form
+	#[serde(default)]
+	pub idp_selection_label: String,
+	/// Field names of the identity provider login form (Shibboleth only)
+	#[serde(default = "default_username_field")]
+	pub username_field: String,
+	#[serde(default = "default_password_field")]
+	pub password_field: String,
+	/// Field name of the one-time code, if the identity provider asks for a second factor
+	#[serde(default = "default_otp_field")]
+	pub otp_field: String,
+	/// Page listing the courses of the user (relative to the base URL)
+	#[serde(default = "default_personal_desktop")]
+	pub personal_desktop: String,
+}
+
+/// How to log into ILIAS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoginMethod {
+	/// Shibboleth single sign-on using the identity provider of the university
+	Shibboleth,
+	/// Login form of ILIAS itself (local accounts)
+	Local,
+}
+
+// not derived, #[default] on enum variants requires Rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for LoginMethod {
+	fn default() -> Self {
+		LoginMethod::Shibboleth
+	}
+}
+
+impl FromStr for LoginMethod {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"shibboleth" => Ok(LoginMethod::Shibboleth),
+			"local" => Ok(LoginMethod::Local),
+			_ => Err(anyhow!("unknown login method {:?}", s)),
+		}
+	}
+}
+
+fn default_username_field() -> String {
+	"j_username".to_owned()
+}
+
+fn default_password_field() -> String {
+	"j_password".to_owned()
+}
+
+fn default_otp_field() -> String {
+	"j_tokenNumber".to_owned()
+}
+
+fn default_personal_desktop() -> String {
+	"ilias.php?baseClass=ilPersonalDesktopGUI&cmd=jumpToSelectedItems".to_owned()
+}
+
+/// Instances that can be used without any configuration
+fn builtin() -> Vec<Instance> {
+	vec![Instance {
+		name: "kit".to_owned(),
+		url: "https://ilias.studium.kit.edu/".to_owned(),
+		login_method: LoginMethod::Shibboleth,
+		idp_entity_id: "https://idp.scc.kit.edu/idp/shibboleth".to_owned(),
+		idp_selection_label: "Mit KIT-Account anmelden".to_owned(),
+		username_field: default_username_field(),
+		password_field: default_password_field(),
+		otp_field: default_otp_field(),
+		personal_desktop: default_personal_desktop(),
+	}]
+}
+
+fn host(url: &str) -> Option<String> {
+	Url::parse(url).ok()?.host_str().map(str::to_owned)
+}
+
+static CURRENT: OnceCell<Instance> = OnceCell::new();
+
+/// Select the instance specified by the options, instances defined in the config files take precedence.
+pub fn init(opt: &Opt) -> Result<()> {
+	let mut instance = opt
+		.instances
+		.iter()
+		.rev()
+		.cloned()
+		.chain(builtin())
+		.find(|x| x.name == opt.instance)
+		.ok_or_else(|| anyhow!("unknown ILIAS instance {:?}", opt.instance))?;
+	Url::parse(&instance.url).with_context(|| format!("invalid URL of instance {:?}", instance.name))?;
+	if !instance.url.ends_with('/') {
+		instance.url.push('/');
+	}
+	if let Some(method) = opt.login_method {
+		instance.login_method = method;
+	}
+	if instance.login_method == LoginMethod::Shibboleth && (instance.idp_entity_id.is_empty() || instance.idp_selection_label.is_empty()) {
+		return Err(anyhow!("instance {:?}: Shibboleth login requires idp_entity_id and idp_selection_label", instance.name));
+	}
+	let _ = CURRENT.set(instance);
+	Ok(())
+}
+
+/// The selected instance, see [`init`]
+pub fn current() -> &'static Instance {
+	CURRENT.get().expect("ILIAS instance not selected")
+}
+
+/// Base URL of the selected instance, ending in a slash
+pub fn ilias_url() -> &'static str {
+	&current().url
+}
+
+impl Instance {
+	/// Host of the ILIAS installation
+	pub fn host(&self) -> Option<String> {
+		host(&self.url)
+	}
+
+	/// Host of the identity provider
+	pub fn idp_host(&self) -> Option<String> {
+		host(&self.idp_entity_id)
+	}
+
+	/// Absolute URL of the personal desktop
+	pub fn personal_desktop_url(&self) -> String {
+		format!("{}{}", self.url, self.personal_desktop)
+	}
+}
@@ -6,75 +6,102 @@ use anyhow::{anyhow, Context, Result};
 use colored::Colorize;
 use futures::future::{self, Either};
 use futures_channel::mpsc::UnboundedSender;
-use futures_util::stream::TryStreamExt;
 use futures_util::StreamExt;
 use ignore::gitignore::Gitignore;
-use indicatif::{ProgressDrawTarget, ProgressStyle};
 use once_cell::sync::{Lazy, OnceCell};
 use scraper::Html;
-use structopt::StructOpt;
-use tokio::{fs, sync::Semaphore, time};
+use tokio::{fs, sync::Semaphore};
 use tokio::task::{self, JoinHandle};
-use tokio_util::io::StreamReader;
 use url::Url;
 
 use std::future::Future;
-use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::collections::HashSet;
 
-pub const ILIAS_URL: &str = "https://ilias.studium.kit.edu/";
-
+mod bandwidth;
 #[macro_use]
 mod cli;
 use cli::*;
+mod config;
+mod errors;
 mod ilias;
+mod instance;
+use instance::ilias_url;
 use ilias::*;
 use Object::*;
+mod plan;
+mod progress;
+mod ratelimit;
+use ratelimit::Rates;
+use plan::Action;
+mod report;
+use report::{Outcome, Record};
+mod retry;
+mod shutdown;
+mod state;
+mod totp;
+use state::*;
 mod util;
 use util::*;
 
 /// Global job queue
 static TASKS: OnceCell<UnboundedSender<JoinHandle<()>>> = OnceCell::new();
 static TASKS_RUNNING: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(0));
-static REQUEST_TICKETS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(0));
-
-pub async fn get_request_ticket() {
-	REQUEST_TICKETS.acquire().await.unwrap().forget();
-}
+/// Local files/folders no longer available on ILIAS (relative to the output directory)
+static REMOVED: Lazy<Mutex<Vec<PathBuf>>> = Lazy::new(|| Mutex::new(Vec::new()));
 
 macro_rules! spawn {
 	($e:expr) => {
-		TASKS.get().unwrap().unbounded_send(task::spawn($e)).unwrap();
+		if !shutdown::requested() {
+			TASKS.get().unwrap().unbounded_send(task::spawn($e)).unwrap();
+		}
 	};
 }
 
 #[tokio::main]
 async fn main() {
-	let opt = Opt::from_args();
-	let rate = opt.rate;
-	task::spawn(async move {
-		let mut interval = time::interval(time::Duration::from_secs_f64(60.0 / rate as f64));
-		loop {
-			interval.tick().await;
-			REQUEST_TICKETS.add_permits(1);
-		}
-	});
-	if let Err(e) = real_main(opt).await {
+	let opt = match config::load_options() {
+		Ok(opt) => opt,
+		Err(e) => {
+			error!(e);
+			std::process::exit(2);
+		},
+	};
+	let result = real_main(opt).await;
+	progress::finish();
+	if let Err(e) = result {
+		errors::record(&e);
 		error!(e);
 	}
+	if shutdown::requested() {
+		report::print_summary();
+	}
+	errors::print_summary();
+	std::process::exit(if shutdown::requested() { shutdown::EXIT_CODE } else { errors::exit_code() });
 }
 
 async fn real_main(mut opt: Opt) -> Result<()> {
 	LOG_LEVEL.store(opt.verbose, Ordering::SeqCst);
+	report::init(opt.report.is_some(), opt.report_events.as_deref())?;
 	#[cfg(windows)]
 	let _ = colored::control::set_virtual_terminal(true);
 
+	// a dry run doesn't create the output directory
+	if !opt.dry_run {
+		create_dir(&opt.output).await.context("failed to create output directory")?;
+	}
 	// use UNC paths on Windows
-	create_dir(&opt.output).await.context("failed to create output directory")?;
-	opt.output = fs::canonicalize(opt.output).await.context("failed to canonicalize output directory")?;
+	if !opt.dry_run || fs::metadata(&opt.output).await.is_ok() {
+		opt.output = fs::canonicalize(opt.output).await.context("failed to canonicalize output directory")?;
+	}
+
+	// remove leftovers of interrupted runs
+	if !opt.dry_run {
+		remove_temp_files(&opt.output);
+	}
 
 	// load .iliasignore file
 	opt.output.push(".iliasignore");
@@ -84,31 +111,19 @@ async fn real_main(mut opt: Opt) -> Result<()> {
 	}
 	opt.output.pop();
 
-	// loac .iliaslogin file
-	opt.output.push(".iliaslogin");
-	let login = std::fs::read_to_string(&opt.output);
-	let (user, pass) = if let Ok(login) = login {
-		let mut lines = login.split('\n');
-		let user = lines.next().context("missing user in .iliaslogin")?;
-		let pass = lines.next().context("missing password in .iliaslogin")?;
-		let user = user.trim();
-		let pass = pass.trim();
-		(user.to_owned(), pass.to_owned())
-	} else {
-		ask_user_pass(&opt).context("credentials input failed")?
-	};
-	opt.output.pop();
+	let state = SyncState::load(&opt.output);
 
-	let ilias = match ILIAS::login(opt, user, pass, ignore).await {
-		Ok(ilias) => ilias,
-		Err(e) => {
-			error!(e);
-			std::process::exit(77);
-		},
-	};
-	if ilias.opt.content_tree {
+	let ilias = ILIAS::login(opt, ignore, state).await.context("login failed")?;
+	// from now on, the run is stopped gracefully to restore the content tree setting and save the state
+	if let Err(e) = shutdown::listen() {
+		warning!("could not install signal handler:", e);
+	}
+	if ilias.opt.content_tree && ilias.opt.dry_run {
+		// switching the view mode changes the user's ILIAS settings
+		warning!(format => "--content-tree is not enabled in a dry run, the plan may be incomplete");
+	} else if ilias.opt.content_tree {
 		// need this to get the content tree
-		if let Err(e) = ilias.download("ilias.php?baseClass=ilRepositoryGUI&cmd=frameset&set_mode=tree&ref_id=1").await {
+		if let Err(e) = ilias.set_view_mode("tree").await {
 			warning!("could not enable content tree:", e);
 		}
 	}
@@ -116,13 +131,7 @@ async fn real_main(mut opt: Opt) -> Result<()> {
 	let (tx, mut rx) = futures_channel::mpsc::unbounded::<JoinHandle<()>>();
 	TASKS.get_or_init(|| tx.clone());
 	TASKS_RUNNING.add_permits(ilias.opt.jobs);
-	PROGRESS_BAR_ENABLED.store(atty::is(atty::Stream::Stdout), Ordering::SeqCst);
-	if PROGRESS_BAR_ENABLED.load(Ordering::SeqCst) {
-		PROGRESS_BAR.set_draw_target(ProgressDrawTarget::stderr_nohz());
-		PROGRESS_BAR.set_style(ProgressStyle::default_bar().template("[{pos}/{len}+] {wide_msg}"));
-		PROGRESS_BAR.inc_length(1);
-		PROGRESS_BAR.set_message("initializing..");
-	}
+	progress::init();
 	if let Some(url) = ilias.opt.sync_url.as_ref() {
 		// TODO: this should be unified with the download logic below
 		let obj = Object::from_url(URL::from_href(url).context("invalid sync URL")?, "Sync URL".to_owned(), None).context("invalid sync object")?; // name can be empty for first element
@@ -130,7 +139,10 @@ async fn real_main(mut opt: Opt) -> Result<()> {
 	} else {
 		let desktop = ilias.personal_desktop().await.context("Failed to load personal desktop")?;
 		for item in desktop.items {
-			let path = ilias.opt.output.join(file_escape(item.name()));
+			if shutdown::requested() {
+				break;
+			}
+			let path = ilias.opt.output.join(local_name(&ilias.opt, &item));
 			tx.unbounded_send(task::spawn(process_gracefully(ilias.clone(), path, item))).unwrap();
 		}
 	}
@@ -142,17 +154,25 @@ async fn real_main(mut opt: Opt) -> Result<()> {
 		}
 	}
 	// channel is empty => all tasks are completed
-	if ilias.opt.content_tree {
+	report_removed(&ilias).await;
+	if ilias.opt.dry_run {
+		plan::print();
+	} else if let Err(e) = ilias.state.save().await {
+		errors::record(&e);
+		error!(e);
+	}
+	if let Some(path) = ilias.opt.report.as_ref() {
+		if let Err(e) = report::write(path) {
+			errors::record(&e);
+			error!(e);
+		}
+	}
+	if ilias.opt.content_tree && !ilias.opt.dry_run {
 		// restore fast page loading times
-		if let Err(e) = ilias.download("ilias.php?baseClass=ilRepositoryGUI&cmd=frameset&set_mode=flat&ref_id=1").await {
+		if let Err(e) = ilias.set_view_mode("flat").await {
 			warning!("could not disable content tree:", e);
 		}
 	}
-	if PROGRESS_BAR_ENABLED.load(Ordering::SeqCst) {
-		PROGRESS_BAR.inc(1);
-		PROGRESS_BAR.set_style(ProgressStyle::default_bar().template("[{pos}/{len}] {wide_msg}"));
-		PROGRESS_BAR.finish_with_message("done");
-	}
 	Ok(())
 }
 
@@ -167,17 +187,43 @@ fn process_gracefully(
 		PROGRESS_BAR.inc_length(1);
 	}
 	let permit = TASKS_RUNNING.acquire().await.unwrap();
+	if shutdown::requested() {
+		return;
+	}
 	let path_text = path.to_string_lossy().into_owned();
-	if let Err(e) = process(ilias, path, obj).await.context("failed to process URL") {
-		error!("Syncing {}", path_text; e);
+	let relative_path = path.strip_prefix(&ilias.opt.output).unwrap_or(&path).to_owned();
+	let (kind, url) = (obj.kind().to_owned(), obj.url().url.clone());
+	let start = Instant::now();
+	let rates = Rates::new(&ilias.opt.for_path(&relative_path));
+	let result = rates.scope(process(ilias, path, obj)).await.context("failed to process URL");
+	match result.as_ref() {
+		Err(e) if shutdown::is_interrupt(e) => {
+			log!(1, "Interrupted {}", path_text);
+		},
+		Err(e) => {
+			errors::record(e);
+			error!("Syncing {}", path_text; e);
+		},
+		Ok(_) => {},
 	}
+	report::record(Record::new(kind, url, relative_path, &result, start.elapsed()));
 	drop(permit);
 }}
 
-async fn handle_gracefully(fut: impl Future<Output = Result<()>>) {
-	if let Err(e) = fut.await {
-		error!(e);
+/// Run a part of processing an object (e.g. saving a forum post image) and report it like an object.
+/// The future returns the number of bytes written.
+async fn handle_gracefully(kind: &str, url: String, relative_path: PathBuf, fut: impl Future<Output = Result<u64>>) {
+	let start = Instant::now();
+	let result = fut.await.map(|bytes| Outcome::Downloaded { bytes });
+	match result.as_ref() {
+		Err(e) if shutdown::is_interrupt(e) => {},
+		Err(e) => {
+			errors::record(e);
+			error!(e);
+		},
+		Ok(_) => {},
 	}
+	report::record(Record::new(kind.to_owned(), url, relative_path, &result, start.elapsed()));
 }
 
 #[allow(non_upper_case_globals)]
@@ -202,10 +248,13 @@ mod selectors {
 	pub static span_small: Lazy<Selector> = Lazy::new(|| Selector::parse("span.small").unwrap());
 	pub static forum_pages: Lazy<Selector> = Lazy::new(|| Selector::parse("div.ilTableNav > table > tbody > tr > td > a").unwrap());
 	pub static alert_danger: Lazy<Selector> = Lazy::new(|| Selector::parse("div.alert-danger").unwrap());
+	pub static saml_response: Lazy<Selector> = Lazy::new(|| Selector::parse(r#"input[name="SAMLResponse"]"#).unwrap());
+	pub static login_form: Lazy<Selector> = Lazy::new(|| Selector::parse(r#"form[name="formlogin"]"#).unwrap());
 	pub static form_group: Lazy<Selector> = Lazy::new(|| Selector::parse(".form-group").unwrap());
 	pub static form_name: Lazy<Selector> = Lazy::new(|| Selector::parse(".il_InfoScreenProperty").unwrap());
+	pub static hist_id_link: Lazy<Selector> = Lazy::new(|| Selector::parse(r#"a[href*="hist_id="]"#).unwrap());
 	pub static cmd_node_regex: Lazy<Regex> = Lazy::new(|| Regex::new(r#"cmdNode=uf:\w\w"#).unwrap());
-	pub static image_src_regex: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\./data/produktiv/mobs/mm_(\d+)/([^?]+).+"#).unwrap());
+	pub static image_src_regex: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\./data/[^/]+/mobs/mm_(\d+)/([^?]+).+"#).unwrap());
 	pub static XOCT_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?m)<script>\s+xoctPaellaPlayer\.init\(([\s\S]+)\)\s+</script>"#).unwrap());
 	pub static il_content_container: Lazy<Selector> = Lazy::new(|| Selector::parse("#il_center_col").unwrap());
 	pub static item_prop: Lazy<Selector> = Lazy::new(|| Selector::parse("span.il_ItemProperty").unwrap());
@@ -216,26 +265,246 @@ use crate::selectors::*;
 
 const NO_ENTRIES: &str = "Keine Einträge";
 
-async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
+/// Name of the local file/folder for the object.
+fn local_name(opt: &Opt, obj: &Object) -> String {
+	match obj {
+		File { name, meta: FileMeta { version: Some(version), .. }, .. }
+			if opt.file_versions == FileVersions::Suffix =>
+		{
+			file_escape(&versioned_name(name, &format!("v{}", version)))
+		},
+		_ => file_escape(obj.name()),
+	}
+}
+
+/// Path used to store an old version of a file.
+fn version_path(opt: &Opt, obj: &Object, path: &Path, version: u32, date: Option<&str>) -> PathBuf {
+	let name = file_escape(obj.name());
+	let dir = path.parent().unwrap();
+	match opt.file_versions {
+		FileVersions::Suffix => dir.join(versioned_name(&name, &format!("v{}", version))),
+		FileVersions::Subfolder => dir.join(".versions").join(versioned_name(&name, &format!("v{}", version))),
+		FileVersions::Rename => {
+			let date = date
+				.and_then(parse_date)
+				.map(|x| x.format("_%Y-%m-%d").to_string())
+				.unwrap_or_default();
+			dir.join(versioned_name(&name, &format!("v{}{}", version, date)))
+		},
+	}
+}
+
+/// Record an old version of the file in the state.
+async fn insert_version(ilias: &ILIAS, obj: &Object, path: &Path, version: u32, date: Option<String>) {
 	let relative_path = path.strip_prefix(&ilias.opt.output).unwrap();
+	ilias.state.insert(Entry {
+		parent: Some(obj.id()),
+		size: file_size(path).await,
+		version: Some(version),
+		date,
+		..Entry::new(format!("{}_v{}", obj.id(), version), "file version", relative_path)
+	});
+}
+
+/// Move the outdated local copy of a file out of the way.
+async fn archive_version(ilias: &ILIAS, opt: &Opt, obj: &Object, path: &Path, previous: Entry) -> Result<()> {
+	let version = previous.version.unwrap_or(1);
+	let target = version_path(opt, obj, path, version, previous.date.as_deref());
+	if fs::metadata(&target).await.is_ok() {
+		warning!(format => "not keeping old version of {}, {} exists already", previous.path.display(), target.display());
+		return Ok(());
+	}
+	create_dir(target.parent().unwrap()).await?;
+	fs::rename(path, &target).await.context("failed to move old version")?;
+	log!(0, "Moved old version to {}", target.strip_prefix(&ilias.opt.output).unwrap().display());
+	insert_version(ilias, obj, &target, version, previous.date).await;
+	Ok(())
+}
+
+/// Download all previous versions of the file listed on its versions page.
+async fn download_versions(ilias: &ILIAS, opt: &Opt, obj: &Object, path: &Path) -> Result<()> {
+	let current = match obj {
+		File { meta: FileMeta { version: Some(version), .. }, .. } => *version,
+		_ => return Ok(()), // only one version
+	};
+	let id = obj.id();
+	let missing = |version: u32| ilias.state.get(&format!("{}_v{}", id, version)).is_none();
+	if !(1..current).any(missing) {
+		return Ok(());
+	}
+	let url = format!(
+		"{}ilias.php?ref_id={}&cmd=versions&cmdClass=ilobjfilegui&baseClass=ilRepositoryGUI",
+		ilias_url(),
+		obj.url().ref_id
+	);
+	let versions = {
+		let html = ilias.get_html(&url).await?;
+		let mut versions = Vec::new();
+		for row in html.select(&tr) {
+			let link = match row.select(&hist_id_link).next().and_then(|x| x.value().attr("href")) {
+				Some(link) => link.to_owned(),
+				None => continue,
+			};
+			let cells = row.select(&td).map(|x| x.text().collect::<String>().trim().to_owned()).collect::<Vec<_>>();
+			let version = cells.iter().find_map(|x| x.parse::<u32>().ok()).context("version number not found")?;
+			let date = cells.into_iter().find(|x| parse_date(x).is_some());
+			versions.push((version, date, link));
+		}
+		versions
+	};
+	for (version, date, link) in versions {
+		if version >= current || !missing(version) {
+			continue;
+		}
+		let target = version_path(opt, obj, path, version, date.as_deref());
+		if fs::metadata(&target).await.is_err() {
+			create_dir(target.parent().unwrap()).await?;
+			log!(0, "Writing {}", target.strip_prefix(&ilias.opt.output).unwrap().display());
+			download_resumable(ilias, &URL::from_href(&link)?.url, &target).await?;
+			set_modified(&target, date.as_deref())?;
+		}
+		insert_version(ilias, obj, &target, version, date).await;
+	}
+	Ok(())
+}
+
+/// Remember local files in the directory that are not listed on ILIAS anymore.
+async fn find_removed(ilias: &ILIAS, path: &Path, names: &HashSet<String>) -> Result<()> {
+	if ilias.opt.dry_run && fs::metadata(path).await.is_err() {
+		return Ok(()); // folder not created yet
+	}
+	let mut entries = fs::read_dir(path).await?;
+	while let Some(entry) = entries.next_entry().await? {
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if name.starts_with('.') || is_temp_file(&name) || names.contains(&name) {
+			continue;
+		}
+		let full_path = entry.path();
+		let relative_path = full_path.strip_prefix(&ilias.opt.output).unwrap();
+		if ilias.ignore.matched(relative_path, entry.file_type().await?.is_dir()).is_ignore() {
+			continue;
+		}
+		if ilias.state.kind_at(relative_path).as_deref() == Some("file version") {
+			continue;
+		}
+		REMOVED.lock().unwrap().push(relative_path.to_owned());
+	}
+	Ok(())
+}
+
+/// List (and optionally archive) the files found by [`find_removed`].
+async fn report_removed(ilias: &ILIAS) {
+	let mut removed = std::mem::take(&mut *REMOVED.lock().unwrap());
+	// folders are checked before their children are processed:
+	// renamed/moved objects were moved away in the meantime, other objects may have been downloaded to that path
+	removed.retain(|path| {
+		ilias.opt.output.join(path).exists()
+			&& !ilias.state.entry_at(path).map(|x| ilias.state.seen_in_this_run(&x)).unwrap_or(false)
+	});
+	if removed.is_empty() {
+		return;
+	}
+	removed.sort();
+	warning!(format => "{} local files/folders are no longer available on ILIAS:", removed.len());
+	for path in removed {
+		if !ilias.opt.archive_removed || ilias.opt.dry_run {
+			log!(0, "  {}", path.display());
+			continue;
+		}
+		let archived = Path::new(".ilias-removed").join(&path);
+		let target = ilias.opt.output.join(&archived);
+		let result = async {
+			if fs::metadata(&target).await.is_ok() {
+				let message = format!("{} exists already", archived.display());
+				return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, message).into());
+			}
+			fs::create_dir_all(target.parent().unwrap()).await?;
+			fs::rename(ilias.opt.output.join(&path), &target).await?;
+			Ok(())
+		}.await;
+		match result {
+			Ok(()) => {
+				log!(0, "  {} (moved to {})", path.display(), archived.display());
+				ilias.state.move_path(&path, &archived);
+			},
+			Err(e) => {
+				errors::record(&e);
+				error!("  {}", path.display(); e);
+			},
+		}
+	}
+}
+
+/// Move the local copy of an object that was renamed or moved on ILIAS.
+async fn move_renamed(ilias: &ILIAS, from: &Path, to: &Path) -> Result<()> {
+	let old_path = ilias.opt.output.join(from);
+	let new_path = ilias.opt.output.join(to);
+	if fs::metadata(&old_path).await.is_err() {
+		return Ok(()); // deleted locally
+	}
+	if fs::metadata(&new_path).await.is_ok() {
+		warning!(format => "{} was moved to {}, but the new path exists already", from.display(), to.display());
+		return Ok(());
+	}
+	if ilias.opt.dry_run {
+		plan::record_move(from, to);
+		return Ok(());
+	}
+	fs::rename(&old_path, &new_path).await.context("failed to move renamed object")?;
+	log!(0, "Moved {} to {}", from.display(), to.display());
+	ilias.state.move_path(from, to);
+	Ok(())
+}
+
+/// Add the download of an object to the dry-run plan.
+async fn plan_download(previous: Option<&Entry>, path: &Path, relative_path: &Path, size: Option<u64>) {
+	let action = if previous.is_some() || fs::metadata(path).await.is_ok() {
+		Action::Changed
+	} else {
+		Action::New
+	};
+	plan::record(action, relative_path, size);
+}
+
+async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<Outcome> {
+	let relative_path = path.strip_prefix(&ilias.opt.output).unwrap();
+	let opt = ilias.opt.for_path(relative_path);
 	if PROGRESS_BAR_ENABLED.load(Ordering::SeqCst) {
 		PROGRESS_BAR.inc(1);
 		PROGRESS_BAR.set_message(relative_path.display().to_string());
 	}
 	if ilias.ignore.matched(relative_path, obj.is_dir()).is_ignore() {
 		log!(1, "Ignored {}", relative_path.to_string_lossy());
-		return Ok(());
+		if ilias.opt.dry_run {
+			plan::record(Action::Ignored, relative_path, None);
+		}
+		return Ok(Outcome::Ignored);
 	}
 	log!(1, "Syncing {} {}", obj.kind(), relative_path.to_string_lossy());
 	log!(2, " URL: {}", obj.url().url);
+	let mut previous = ilias.state.get(&obj.id());
+	if let Some(entry) = previous.as_ref() {
+		let new_version = matches!(&obj, File { meta, .. } if meta.version != entry.version);
+		if entry.path != relative_path && !new_version && !ilias.state.seen_in_this_run(entry) {
+			move_renamed(&ilias, &entry.path, relative_path).await?;
+			if ilias.opt.dry_run && fs::metadata(ilias.opt.output.join(&entry.path)).await.is_ok() {
+				return Ok(Outcome::Skipped); // the contents of moved folders are not checked
+			}
+			previous = ilias.state.get(&obj.id());
+		}
+	}
 	if obj.is_dir() {
-		create_dir(&path).await?;
+		if !ilias.opt.dry_run {
+			create_dir(&path).await?;
+		}
+		ilias.state.insert(Entry::from_object(&obj, relative_path));
 	}
+	let mut bytes = 0;
 	match &obj {
 		Course { url, name } => {
 			let content = if ilias.opt.content_tree {
 				let html = ilias.download(&url.url).await?.text().await?;
-				let cmd_node = cmd_node_regex.find(&html).context("can't find cmdNode")?.as_str()[8..].to_owned();
+				let cmd_node = cmd_node_regex.find(&html).ok_or(IliasError::PageStructure("cmd_node_regex"))?.as_str()[8..].to_owned();
 				let content_tree = ilias.get_course_content_tree(&url.ref_id, &cmd_node).await;
 				match content_tree {
 					Ok(tree) => (tree.into_iter().map(Result::Ok).collect(), None),
@@ -243,7 +512,7 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 						// some folders are hidden on the course page and can only be found via the RSS feed / recent activity / content tree sidebar
 						// TODO: this is probably never the case for folders?
 						if html.contains(r#"input[name="cmd[join]""#) {
-							return Ok(()); // ignore groups we are not in
+							return Ok(Outcome::Skipped); // ignore groups we are not in
 						}
 						warning!(name, "falling back to incomplete course content extractor!", e);
 						ilias.get_course_content(&url).await? // TODO: perhaps don't download almost the same content 3x
@@ -252,52 +521,87 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 			} else {
 				ilias.get_course_content(&url).await?
 			};
-			if let Some(s) = content.1.as_ref() {
+			if let Some(s) = content.1.as_ref().filter(|_| !ilias.opt.dry_run) {
 				let path = path.join("course.html");
-				write_file_data(&path, &mut s.as_bytes()).await.context("failed to write course page html")?;
+				bytes += write_file_data(&path, &mut s.as_bytes()).await.context("failed to write course page html")?;
 			}
+			let mut names = HashSet::new();
+			names.insert("course.html".to_owned());
 			for item in content.0 {
 				let item = item?;
-				let path = path.join(file_escape(item.name()));
+				names.insert(local_name(&opt, &item));
+				let path = path.join(local_name(&opt, &item));
 				let ilias = Arc::clone(&ilias);
 				spawn!(process_gracefully(ilias, path, item));
 			}
+			// the content tree does not include sub-folders
+			if !ilias.opt.content_tree {
+				find_removed(&ilias, &path, &names).await?;
+			}
 		},
 		Folder { url, .. } => {
 			let content = ilias.get_course_content(&url).await?;
-			if let Some(s) = content.1.as_ref() {
+			if let Some(s) = content.1.as_ref().filter(|_| !ilias.opt.dry_run) {
 				let path = path.join("folder.html");
-				write_file_data(&path, &mut s.as_bytes()).await.context("failed to write folder page html")?;
+				bytes += write_file_data(&path, &mut s.as_bytes()).await.context("failed to write folder page html")?;
 			}
+			let mut names = HashSet::new();
+			names.insert("folder.html".to_owned());
 			for item in content.0 {
 				let item = item?;
-				let path = path.join(file_escape(item.name()));
+				names.insert(local_name(&opt, &item));
+				let path = path.join(local_name(&opt, &item));
 				let ilias = Arc::clone(&ilias);
 				spawn!(process_gracefully(ilias, path, item));
 			}
+			find_removed(&ilias, &path, &names).await?;
 		},
-		File { url, .. } => {
-			if ilias.opt.skip_files {
-				return Ok(());
+		File { url, meta, .. } => {
+			if opt.skip_files {
+				return Ok(Outcome::Skipped);
 			}
-			if !ilias.opt.force && fs::metadata(&path).await.is_ok() {
+			// files downloaded before the state file existed are assumed to be up-to-date
+			let unchanged = previous
+				.as_ref()
+				.map(|x| x.path == relative_path && x.version == meta.version)
+				.unwrap_or(true);
+			let entry = Entry::from_object(&obj, relative_path).with_meta(meta);
+			let mut outcome = Outcome::Skipped;
+			if !opt.force && unchanged && fs::metadata(&path).await.is_ok() {
 				log!(2, "Skipping download, file exists already");
-				return Ok(());
+			} else if ilias.opt.dry_run {
+				plan_download(previous.as_ref(), &path, relative_path, meta.size).await;
+				return Ok(Outcome::Skipped);
+			} else {
+				if let Some(previous) = previous.filter(|x| x.version != meta.version) {
+					let previous_path = ilias.opt.output.join(&previous.path);
+					if previous.path == relative_path {
+						if opt.file_versions != FileVersions::Suffix && fs::metadata(&path).await.is_ok() {
+							archive_version(&ilias, &opt, &obj, &path, previous).await?;
+						}
+					} else if opt.file_versions == FileVersions::Suffix && fs::metadata(&previous_path).await.is_ok() {
+						// the previous version is kept under its own name, don't report it as removed
+						let version = previous.version.unwrap_or(1);
+						insert_version(&ilias, &obj, &previous_path, version, previous.date).await;
+					}
+				}
+				log!(0, "Writing {}", relative_path.to_string_lossy());
+				outcome = Outcome::Downloaded { bytes: download_resumable(&ilias, &url.url, &path).await? };
+				set_modified(&path, meta.date.as_deref())?;
+			}
+			ilias.state.insert(Entry { size: file_size(&path).await, ..entry });
+			if opt.all_versions && !ilias.opt.dry_run {
+				download_versions(&ilias, &opt, &obj, &path).await.context("failed to download previous versions")?;
 			}
-			let data = ilias.download(&url.url).await?;
-			let mut reader = StreamReader::new(data.bytes_stream().map_err(|x| {
-				io::Error::new(io::ErrorKind::Other, x)
-			}));
-			log!(0, "Writing {}", relative_path.to_string_lossy());
-			write_file_data(&path, &mut reader).await?;
+			return Ok(outcome);
 		},
 		PluginDispatch { url, .. } => {
-			if ilias.opt.no_videos {
-				return Ok(());
+			if opt.no_videos {
+				return Ok(Outcome::Skipped);
 			}
 			let full_url = {
 				// first find the link to full video list
-				let list_url = format!("{}ilias.php?ref_id={}&cmdClass=xocteventgui&cmdNode=nc:n4:14u&baseClass=ilObjPluginDispatchGUI&lang=de&limit=20&cmd=asyncGetTableGUI&cmdMode=asynch", ILIAS_URL, url.ref_id);
+				let list_url = format!("{}ilias.php?ref_id={}&cmdClass=xocteventgui&cmdNode=nc:n4:14u&baseClass=ilObjPluginDispatchGUI&lang=de&limit=20&cmd=asyncGetTableGUI&cmdMode=asynch", ilias_url(), url.ref_id);
 				log!(1, "Loading {}", list_url);
 				let data = ilias.download(&list_url).await?;
 				let html = data.text().await?;
@@ -305,10 +609,10 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 				html.select(&a)
 					.filter_map(|link| link.value().attr("href"))
 					.filter(|href| href.contains("trows=800"))
-					.map(|x| x.to_string()).next().context("video list link not found")?
+					.map(|x| x.to_string()).next().ok_or(IliasError::PageStructure("video list link"))?
 			};
 			log!(1, "Rewriting {}", full_url);
-			let mut full_url = Url::parse(&format!("{}{}", ILIAS_URL, full_url))?;
+			let mut full_url = Url::parse(&format!("{}{}", ilias_url(), full_url))?;
 			let mut query_parameters = full_url.query_pairs().map(|(x, y)| (x.into_owned(), y.into_owned())).collect::<Vec<_>>();
 			for (key, value) in &mut query_parameters {
 				match key.as_ref() {
@@ -342,6 +646,10 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					let mut path = path.clone();
 					path.push(format!("{}.mp4", file_escape(title)));
 					log!(1, "Found video: {}", title);
+					let date = row
+						.select(&td)
+						.map(|x| x.text().collect::<String>().trim().to_owned())
+						.find(|x| parse_date(x).is_some());
 					let video = Video {
 						url: URL::raw(
 							link.value()
@@ -349,6 +657,7 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 								.context("video link without href")?
 								.to_owned(),
 						),
+						meta: FileMeta { date, ..FileMeta::default() },
 					};
 					let ilias = Arc::clone(&ilias);
 					spawn!(async {
@@ -357,21 +666,24 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 				}
 			}
 		},
-		Video { url } => {
-			if ilias.opt.no_videos {
-				return Ok(());
+		Video { url, meta } => {
+			if opt.no_videos {
+				return Ok(Outcome::Skipped);
 			}
-			if fs::metadata(&path).await.is_ok() && !(ilias.opt.force || ilias.opt.check_videos) {
+			let unchanged = previous.as_ref().map(|x| x.path == relative_path).unwrap_or(true);
+			let entry = Entry::from_object(&obj, relative_path).with_meta(meta);
+			if unchanged && fs::metadata(&path).await.is_ok() && !(opt.force || opt.check_videos) {
 				log!(2, "Skipping download, file exists already");
-				return Ok(());
+				ilias.state.insert(Entry { size: file_size(&path).await, ..entry });
+				return Ok(Outcome::Skipped);
 			}
-			let url = format!("{}{}", ILIAS_URL, url.url);
+			let url = format!("{}{}", ilias_url(), url.url);
 			let data = ilias.download(&url);
 			let html = data.await?.text().await?;
 			log!(2, "{}", html);
 			let json: serde_json::Value = {
 				let mut json_capture = XOCT_REGEX.captures_iter(&html);
-				let json = &json_capture.next().context("xoct player json not found")?[1];
+				let json = &json_capture.next().ok_or(IliasError::PageStructure("XOCT_REGEX"))?[1];
 				log!(2, "{}", json);
 				let json = json
 					.split(",\n")
@@ -382,33 +694,45 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 			log!(2, "{}", json);
 			let url = json
 				.pointer("/streams/0/sources/mp4/0/src")
-				.context("video src not found")?
+				.ok_or(IliasError::PageStructure("video src"))?
 				.as_str()
 				.context("video src not string")?;
-			let meta = fs::metadata(&path).await;
-			if !ilias.opt.force && meta.is_ok() && ilias.opt.check_videos {
+			let local_meta = fs::metadata(&path).await;
+			let mut outcome = Outcome::Skipped;
+			if !opt.force && unchanged && local_meta.is_ok() && opt.check_videos {
 				let head = ilias
 					.head(url)
 					.await
 					.context("HEAD request failed")?;
 				if let Some(len) = head.headers().get("content-length") {
-					if meta?.len() != len.to_str()?.parse::<u64>()? {
-						warning!(relative_path.to_string_lossy(), "was updated, consider moving the outdated file");
+					let len = len.to_str()?.parse::<u64>()?;
+					if local_meta?.len() != len {
+						if ilias.opt.dry_run {
+							plan::record(Action::Changed, relative_path, Some(len));
+						} else {
+							warning!(relative_path.to_string_lossy(), "was updated, consider moving the outdated file");
+						}
 					}
 				}
+			} else if ilias.opt.dry_run {
+				let head = ilias.head(url).await.context("HEAD request failed")?;
+				let len = head
+					.headers()
+					.get("content-length")
+					.and_then(|x| x.to_str().ok()?.parse::<u64>().ok());
+				plan_download(previous.as_ref(), &path, relative_path, len).await;
+				return Ok(Outcome::Skipped);
 			} else {
-				let resp = ilias.download(&url).await?;
-				let mut reader = StreamReader::new(
-				resp.bytes_stream()
-						.map_err(|x| io::Error::new(io::ErrorKind::Other, x)),
-				);
 				log!(0, "Writing {}", relative_path.to_string_lossy());
-				write_file_data(&path, &mut reader).await?;
+				outcome = Outcome::Downloaded { bytes: download_resumable(&ilias, url, &path).await? };
+				set_modified(&path, meta.date.as_deref())?;
 			}
+			ilias.state.insert(Entry { size: file_size(&path).await, ..entry });
+			return Ok(outcome);
 		},
 		Forum { url, .. } => {
-			if !ilias.opt.forum {
-				return Ok(());
+			if !opt.forum {
+				return Ok(Outcome::Skipped);
 			}
 			let url = &url.url;
 			let html = {
@@ -422,12 +746,12 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					if thread_count_selector.is_none() {
 						if let Some(cell) = html.select(&td).next() {
 							if cell.text().any(|x| x == NO_ENTRIES) {
-								return Ok(()); // empty forum
+								return Ok(Outcome::Skipped); // empty forum
 							}
 						}
 					}
 					thread_count_selector
-						.context("can't find forum thread count selector (empty forum?)")?
+						.ok_or(IliasError::PageStructure("forum thread count link"))?
 						.to_owned()
 				};
 				let data = ilias.download(&url);
@@ -452,7 +776,7 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 				let link = cells[1]
 					.select(&a)
 					.next()
-					.context("thread link not found")?;
+					.ok_or(IliasError::PageStructure("thread link"))?;
 				let object = Object::from_link(link, link)?;
 				let mut path = path.clone();
 				let name = format!(
@@ -465,13 +789,17 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					link.text().collect::<String>().trim()
 				);
 				path.push(file_escape(&name));
-				// TODO: set modification date?
-				let saved_posts = {
-					match std::fs::read_dir(&path) {
-						// TODO: make this async
-						Ok(stream) => stream.count(),
-						Err(_) => 0,
-					}
+				let saved_posts = if ilias.state.get(&object.id()).is_some() {
+					ilias.state.child_count(&object.id())
+				} else {
+					// not in the state yet (e.g. first run with a state file): count the saved posts
+					std::fs::read_dir(&path)
+						.map(|dir| {
+							dir.filter_map(|x| x.ok())
+								.filter(|x| x.file_name().to_string_lossy().ends_with(".html"))
+								.count()
+						})
+						.unwrap_or(0)
 				};
 				let available_posts = cells[3]
 					.text()
@@ -480,7 +808,7 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					.trim()
 					.parse::<usize>()
 					.context("parsing post count failed")?;
-				if available_posts <= saved_posts && !ilias.opt.force {
+				if available_posts <= saved_posts && !opt.force {
 					continue;
 				}
 				let ilias = Arc::clone(&ilias);
@@ -491,8 +819,8 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 			}
 		},
 		Thread { url } => {
-			if !ilias.opt.forum {
-				return Ok(());
+			if !opt.forum {
+				return Ok(Outcome::Skipped);
 			}
 			let mut all_images = Vec::new();
 			let mut attachments = Vec::new();
@@ -502,14 +830,15 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					let title = post
 						.select(&post_title)
 						.next()
-						.context("post title not found")?
+						.ok_or(IliasError::PageStructure("post_title"))?
 						.text()
 						.collect::<String>();
 					let author = post
 						.select(&span_small)
 						.next()
-						.context("post author not found")?;
+						.ok_or(IliasError::PageStructure("span_small"))?;
 					let author = author.text().collect::<String>();
+					let date = parse_date(&author).map(|_| author.clone());
 					let author = author
 						.trim()
 						.split('|')
@@ -528,8 +857,8 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					let container = post
 						.select(&post_container)
 						.next()
-						.context("post container not found")?;
-					let link = container.select(&a).next().context("post link not found")?;
+						.ok_or(IliasError::PageStructure("post_container"))?;
+					let link = container.select(&a).next().ok_or(IliasError::PageStructure("post link"))?;
 					let id = link
 						.value()
 						.attr("id")
@@ -539,12 +868,27 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					let data = container.inner_html();
 					let path = path.join(file_escape(&name));
 					let relative_path = relative_path.join(file_escape(&name));
-					spawn!(handle_gracefully(async move {
-						log!(0, "Writing {}", relative_path.display());
-						write_file_data(&path, &mut data.as_bytes())
-							.await
-							.context("failed to write forum post")
-					}));
+					let entry = Entry {
+						parent: Some(obj.id()),
+						date,
+						..Entry::new(format!("pos_{}", id), "post", &relative_path)
+					};
+					if ilias.opt.dry_run {
+						if !path.exists() {
+							plan::record(Action::New, &relative_path, Some(data.len() as u64));
+						}
+					} else {
+						let ilias = Arc::clone(&ilias);
+						spawn!(handle_gracefully("post", url.url.clone(), relative_path.clone(), async move {
+							log!(0, "Writing {}", relative_path.display());
+							let bytes = write_file_data(&path, &mut data.as_bytes())
+								.await
+								.context("failed to write forum post")?;
+							set_modified(&path, entry.date.as_deref())?;
+							ilias.state.insert(entry);
+							Ok(bytes)
+						}));
+					}
 					let images = container
 						.select(&img)
 						.map(|x| x.value().attr("src").map(|x| x.to_owned()));
@@ -588,7 +932,6 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 			}
 			for (id, image) in all_images {
 				let src = URL::from_href(&image)?;
-				let dl = ilias.download(&src.url).await?;
 				let mut path = path.clone();
 				if let Some(m) = image_src_regex.captures(&image) {
 					// image uploaded to ILIAS
@@ -598,25 +941,36 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					// external image
 					path.push(file_escape(&format!("{}_{}", id, image)));
 				}
-				spawn!(handle_gracefully(async move {
-					let bytes = dl.bytes().await?;
-					write_file_data(&path, &mut &*bytes)
+				if ilias.opt.dry_run {
+					if fs::metadata(&path).await.is_err() {
+						plan::record(Action::New, path.strip_prefix(&ilias.opt.output).unwrap(), None);
+					}
+					continue;
+				}
+				let relative_path = path.strip_prefix(&ilias.opt.output).unwrap().to_owned();
+				handle_gracefully("image", src.url.clone(), relative_path, async {
+					download_resumable(&ilias, &src.url, &path)
 						.await
-						.context("failed to write forum post image attachment")
-				}));
+						.context("failed to download forum post image attachment")
+				}).await;
 			}
 			for (id, name, url) in attachments {
 				let url = url.context("attachment without href")?;
 				let src = URL::from_href(&url)?;
-				let dl = ilias.download(&src.url).await?;
 				let mut path = path.clone();
 				path.push(file_escape(&format!("{}_{}", id, name)));
-				spawn!(handle_gracefully(async move {
-					let bytes = dl.bytes().await?;
-					write_file_data(&path, &mut &*bytes)
+				if ilias.opt.dry_run {
+					if fs::metadata(&path).await.is_err() {
+						plan::record(Action::New, path.strip_prefix(&ilias.opt.output).unwrap(), None);
+					}
+					continue;
+				}
+				let relative_path = path.strip_prefix(&ilias.opt.output).unwrap().to_owned();
+				handle_gracefully("attachment", src.url.clone(), relative_path, async {
+					download_resumable(&ilias, &src.url, &path)
 						.await
-						.context("failed to write forum post file attachment")
-				}));
+						.context("failed to download forum post file attachment")
+				}).await;
 			}
 		},
 		ExerciseHandler { url, .. } => {
@@ -645,12 +999,12 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 				let name = row
 					.select(&form_name)
 					.next()
-					.context("link without file name")?
+					.ok_or(IliasError::PageStructure("form_name"))?
 					.text()
 					.collect::<String>()
 					.trim()
 					.to_owned();
-				let item = File { url, name };
+				let item = File { url, name, meta: FileMeta::default() };
 				let mut path = path.clone();
 				// handle files with the same name
 				let filename = file_escape(item.name());
@@ -674,16 +1028,22 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 			}
 		},
 		Weblink { url, .. } => {
-			if !ilias.opt.force && fs::metadata(&path).await.is_ok() {
+			let unchanged = previous.as_ref().map(|x| x.path == relative_path).unwrap_or(true);
+			if !opt.force && unchanged && fs::metadata(&path).await.is_ok() {
 				log!(2, "Skipping download, link exists already");
-				return Ok(());
+				ilias.state.insert(Entry::from_object(&obj, relative_path));
+				return Ok(Outcome::Skipped);
+			}
+			if ilias.opt.dry_run {
+				plan_download(previous.as_ref(), &path, relative_path, None).await;
+				return Ok(Outcome::Skipped);
 			}
 			let head_req_result = ilias.head(&url.url).await;
 			let url = match &head_req_result {
 				Err(e) => e.url().context("HEAD request failed")?.as_str(),
 				Ok(head) => head.url().as_str(),
 			};
-			if url.starts_with(ILIAS_URL) {
+			if url.starts_with(ilias_url()) {
 				// is a link list
 				if fs::metadata(&path).await.is_err() {
 					create_dir(&path).await?;
@@ -718,25 +1078,32 @@ async fn process(ilias: Arc<ILIAS>, path: PathBuf, obj: Object) -> Result<()> {
 					}
 					let head = head.unwrap();
 					let url = head.url().as_str();
-					write_file_data(path.join(file_escape(&name)), &mut url.as_bytes()).await?;
+					bytes += write_file_data(path.join(file_escape(&name)), &mut url.as_bytes()).await?;
 				}
 			} else {
 				log!(0, "Writing {}", relative_path.to_string_lossy());
-				write_file_data(&path, &mut url.as_bytes()).await.context("failed to save weblink URL")?;
+				bytes += write_file_data(&path, &mut url.as_bytes()).await.context("failed to save weblink URL")?;
 			}
+			ilias.state.insert(Entry::from_object(&obj, relative_path));
+			return Ok(Outcome::Downloaded { bytes });
 		},
 		Wiki { .. } => {
 			log!(1, "Ignored wiki!");
+			return Ok(Outcome::Ignored);
 		},
 		Survey { .. } => {
 			log!(1, "Ignored survey!");
+			return Ok(Outcome::Ignored);
 		},
 		Presentation { .. } => {
 			log!(1, "Ignored interactive presentation! (visit it yourself, it's probably interesting)");
+			return Ok(Outcome::Ignored);
 		},
 		Generic { .. } => {
-			log!(1, "Ignored generic {:?}", obj)
+			log!(1, "Ignored generic {:?}", obj);
+			return Ok(Outcome::Ignored);
 		},
 	}
-	Ok(())
+	// the contents of containers are processed (and reported) separately
+	Ok(Outcome::Listed { bytes })
 }
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use colored::Colorize;
+use once_cell::sync::Lazy;
+
+/// What a real run would do with a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+	New,
+	Changed,
+	Moved,
+	Ignored,
+}
+
+#[derive(Debug)]
+struct Planned {
+	action: Action,
+	/// Local path, relative to the output directory
+	path: PathBuf,
+	/// Previous path of moved objects
+	from: Option<PathBuf>,
+	/// Expected download size in bytes (if known)
+	size: Option<u64>,
+}
+
+/// Actions collected in `--dry-run` mode
+static PLAN: Lazy<Mutex<Vec<Planned>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn record(action: Action, path: &Path, size: Option<u64>) {
+	PLAN.lock().unwrap().push(Planned {
+		action,
+		path: path.to_owned(),
+		from: None,
+		size,
+	});
+}
+
+pub fn record_move(from: &Path, to: &Path) {
+	PLAN.lock().unwrap().push(Planned {
+		action: Action::Moved,
+		path: to.to_owned(),
+		from: Some(from.to_owned()),
+		size: None,
+	});
+}
+
+/// Print the collected actions and the estimated download size.
+pub fn print() {
+	let mut plan = std::mem::take(&mut *PLAN.lock().unwrap());
+	plan.sort_by(|a, b| (a.action, &a.path).cmp(&(b.action, &b.path)));
+	let mut counts = [0; 4];
+	let mut bytes = 0;
+	let mut unknown = 0;
+	for item in &plan {
+		counts[item.action as usize] += 1;
+		let text = match item.action {
+			Action::New => format!("new      {}", item.path.display()).bright_green(),
+			Action::Changed => format!("changed  {}", item.path.display()).bright_yellow(),
+			Action::Moved => format!(
+				"moved    {} -> {}",
+				item.from.as_deref().unwrap_or(&item.path).display(),
+				item.path.display()
+			).normal(),
+			Action::Ignored => format!("ignored  {}", item.path.display()).dimmed(),
+		};
+		if matches!(item.action, Action::New | Action::Changed) {
+			match item.size {
+				Some(size) => {
+					bytes += size;
+					log!(0, "{} ({})", text, format_size(size));
+				},
+				None => {
+					unknown += 1;
+					log!(0, "{}", text);
+				},
+			}
+		} else {
+			log!(0, "{}", text);
+		}
+	}
+	log!(
+		0,
+		"{} new, {} changed, {} moved, {} ignored; about {} to download{}",
+		counts[Action::New as usize],
+		counts[Action::Changed as usize],
+		counts[Action::Moved as usize],
+		counts[Action::Ignored as usize],
+		format_size(bytes),
+		if unknown > 0 {
+			format!(" (+ {} files of unknown size)", unknown)
+		} else {
+			String::new()
+		}
+	);
+}
+
+pub fn format_size(bytes: u64) -> String {
+	const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+	if bytes < 1024 {
+		return format!("{} bytes", bytes);
+	}
+	let mut size = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit + 1 < UNITS.len() {
+		size /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", size, UNITS[unit])
+}
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use once_cell::sync::Lazy;
+
+use crate::cli::{MULTI_PROGRESS, PROGRESS_BAR, PROGRESS_BAR_ENABLED};
+
+/// Interval of the progress lines printed if stdout is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Thread drawing the progress bars
+static DRAW_THREAD: Lazy<Mutex<Option<JoinHandle<()>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Show the progress bars, if stdout is a terminal.
+pub fn init() {
+	PROGRESS_BAR_ENABLED.store(atty::is(atty::Stream::Stdout), Ordering::SeqCst);
+	if !PROGRESS_BAR_ENABLED.load(Ordering::SeqCst) {
+		return;
+	}
+	MULTI_PROGRESS.add(PROGRESS_BAR.clone());
+	PROGRESS_BAR.set_style(ProgressStyle::default_bar().template("[{pos}/{len}+] {wide_msg}"));
+	PROGRESS_BAR.inc_length(1);
+	PROGRESS_BAR.set_message("initializing..");
+	// the bars are only drawn while joined, which blocks until all of them are finished
+	*DRAW_THREAD.lock().unwrap() = Some(thread::spawn(|| {
+		let _ = MULTI_PROGRESS.join();
+	}));
+}
+
+/// Finish the progress bars and wait until they are drawn.
+/// Messages are printed directly afterwards.
+pub fn finish() {
+	let thread = match DRAW_THREAD.lock().unwrap().take() {
+		Some(thread) => thread,
+		None => return,
+	};
+	PROGRESS_BAR.inc(1);
+	PROGRESS_BAR.set_style(ProgressStyle::default_bar().template("[{pos}/{len}] {wide_msg}"));
+	PROGRESS_BAR.finish_with_message("done");
+	let _ = thread.join();
+	PROGRESS_BAR_ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Progress of a single download: a progress bar if stdout is a terminal, periodic log lines otherwise.
+/// The bar is removed when the value is dropped.
+pub struct Download {
+	name: String,
+	bar: Option<ProgressBar>,
+	/// Bytes written to the file and total size (if known)
+	position: u64,
+	length: Option<u64>,
+	/// Bytes received in this run, used to calculate the rate
+	received: u64,
+	started: Instant,
+	last_log: Instant,
+}
+
+impl Download {
+	pub fn new(path: &Path) -> Self {
+		let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+		let bar = if PROGRESS_BAR_ENABLED.load(Ordering::SeqCst) {
+			let bar = MULTI_PROGRESS.add(ProgressBar::new(0));
+			bar.set_style(ProgressStyle::default_spinner().template("  {wide_msg} {bytes} {bytes_per_sec}"));
+			bar.set_message(name.clone());
+			bar.set_draw_rate(4);
+			Some(bar)
+		} else {
+			None
+		};
+		let now = Instant::now();
+		Download {
+			name,
+			bar,
+			position: 0,
+			length: None,
+			received: 0,
+			started: now,
+			last_log: now,
+		}
+	}
+
+	/// (Re)start the transfer at `position`, `length` is the total size from `Content-Length`.
+	pub fn start(&mut self, position: u64, length: Option<u64>) {
+		self.position = position;
+		self.length = length;
+		if let Some(bar) = self.bar.as_ref() {
+			if let Some(length) = length {
+				bar.set_style(
+					ProgressStyle::default_bar()
+						.template("  {wide_msg} [{bar:20}] {bytes}/{total_bytes} {bytes_per_sec} {eta}")
+						.progress_chars("=> "),
+				);
+				bar.set_length(length);
+			}
+			bar.set_position(position);
+			bar.reset_eta();
+		}
+	}
+
+	/// Account for the received bytes.
+	pub fn inc(&mut self, bytes: u64) {
+		self.position += bytes;
+		self.received += bytes;
+		if let Some(bar) = self.bar.as_ref() {
+			bar.inc(bytes);
+		} else if self.last_log.elapsed() >= LOG_INTERVAL {
+			self.last_log = Instant::now();
+			let rate = HumanBytes((self.received as f64 / self.started.elapsed().as_secs_f64()) as u64);
+			match self.length {
+				Some(length) if length > 0 => {
+					log!(0, "Downloading {}: {}% of {} ({}/s)", self.name, self.position * 100 / length, HumanBytes(length), rate);
+				},
+				_ => {
+					log!(0, "Downloading {}: {} ({}/s)", self.name, HumanBytes(self.position), rate);
+				},
+			}
+		}
+	}
+}
+
+impl Drop for Download {
+	fn drop(&mut self) {
+		if let Some(bar) = self.bar.take() {
+			bar.finish_and_clear();
+		}
+	}
+}
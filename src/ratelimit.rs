@@ -0,0 +1,333 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use colored::Colorize;
+use reqwest::Url;
+use tokio::time::{self, Instant};
+
+use crate::cli::Opt;
+
+/// Lowest rate after repeated slowdowns, relative to the configured rate
+const MIN_SLOWDOWN: f64 = 1.0 / 16.0;
+
+/// Token bucket allowing `rate` requests per second, with bursts of up to `burst` requests.
+pub struct TokenBucket {
+	rate: f64,
+	burst: f64,
+	state: Mutex<BucketState>,
+	/// Budget shared with other buckets, requests need a token from it too
+	parent: Option<Arc<TokenBucket>>,
+}
+
+struct BucketState {
+	tokens: f64,
+	last_refill: Instant,
+	/// Factor applied to the rate, reduced when the server asks us to slow down
+	slowdown: f64,
+}
+
+impl TokenBucket {
+	pub fn new(per_minute: usize, burst: usize) -> Self {
+		let burst = burst.max(1) as f64;
+		TokenBucket {
+			rate: per_minute.max(1) as f64 / 60.0,
+			burst,
+			state: Mutex::new(BucketState {
+				tokens: burst,
+				last_refill: Instant::now(),
+				slowdown: 1.0,
+			}),
+			parent: None,
+		}
+	}
+
+	/// Additionally limit the requests by the (shared) parent bucket.
+	pub fn with_parent(mut self, parent: Arc<TokenBucket>) -> Self {
+		self.parent = Some(parent);
+		self
+	}
+
+	/// Wait until a request may be sent.
+	pub async fn acquire(&self) {
+		self.acquire_own().await;
+		if let Some(parent) = self.parent.as_ref() {
+			parent.acquire_own().await;
+		}
+	}
+
+	async fn acquire_own(&self) {
+		loop {
+			let wait = {
+				let mut state = self.state.lock().unwrap();
+				let now = Instant::now();
+				let rate = self.rate * state.slowdown;
+				state.tokens = (state.tokens + (now - state.last_refill).as_secs_f64() * rate).min(self.burst);
+				state.last_refill = now;
+				if state.tokens >= 1.0 {
+					state.tokens -= 1.0;
+					return;
+				}
+				Duration::from_secs_f64((1.0 - state.tokens) / rate)
+			};
+			time::sleep(wait).await;
+		}
+	}
+
+	/// Halve the rate, because the server responded with 429 Too Many Requests / 503 Service Unavailable.
+	pub fn slow_down(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.slowdown = (state.slowdown / 2.0).max(MIN_SLOWDOWN);
+		state.tokens = state.tokens.min(0.0);
+		match self.parent.as_ref() {
+			Some(parent) => parent.slow_down(),
+			None => {
+				warning!(format => "server is overloaded, reducing request rate to {:.1}/min", self.rate * state.slowdown * 60.0);
+			},
+		}
+	}
+
+	/// Slowly return to the configured rate after successful requests.
+	pub fn speed_up(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.slowdown = (state.slowdown * 1.1).min(1.0);
+		if let Some(parent) = self.parent.as_ref() {
+			parent.speed_up();
+		}
+	}
+}
+
+/// Kind of request, each kind has its own budget (see [`RateLimiter`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+	/// ILIAS pages and small requests
+	Page,
+	/// (Large) file downloads from ILIAS
+	Download,
+}
+
+tokio::task_local! {
+	/// Request rates of the course or folder processed by the current task
+	static RATES: Rates;
+}
+
+/// Request rates per minute, can be lowered for single courses in the config file (see [`Override`](crate::config::Override)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rates {
+	pub rate: usize,
+	pub media_rate: usize,
+}
+
+impl Rates {
+	pub fn new(opt: &Opt) -> Self {
+		Rates {
+			rate: opt.rate,
+			media_rate: opt.media_rate,
+		}
+	}
+
+	/// Apply the rates to all requests sent by the future.
+	pub async fn scope<F: Future>(self, future: F) -> F::Output {
+		RATES.scope(self, future).await
+	}
+}
+
+/// Buckets limiting a request: the bucket of the host and request kind,
+/// and the bucket of the overridden rate (if any).
+pub struct Budget {
+	bucket: Arc<TokenBucket>,
+	custom: Option<Arc<TokenBucket>>,
+}
+
+impl Budget {
+	/// Wait until a request may be sent.
+	pub async fn acquire(&self) {
+		if let Some(custom) = self.custom.as_ref() {
+			custom.acquire().await;
+		}
+		self.bucket.acquire().await;
+	}
+
+	/// See [`TokenBucket::slow_down`], the overridden rate is only an additional limit.
+	pub fn slow_down(&self) {
+		self.bucket.slow_down();
+	}
+
+	pub fn speed_up(&self) {
+		self.bucket.speed_up();
+	}
+}
+
+/// Request budgets for ILIAS pages, ILIAS file downloads and every other host (mostly Opencast media).
+/// File downloads also count towards the page budget, so `--rate` limits all requests to ILIAS.
+pub struct RateLimiter {
+	ilias_host: String,
+	rate: usize,
+	pages: Arc<TokenBucket>,
+	downloads: Arc<TokenBucket>,
+	media_rate: usize,
+	burst: usize,
+	/// One bucket per host
+	media: Mutex<HashMap<String, Arc<TokenBucket>>>,
+	/// Buckets of overridden rates, per host and rate (courses with the same rate share a bucket)
+	custom: Mutex<HashMap<(String, usize), Arc<TokenBucket>>>,
+}
+
+impl RateLimiter {
+	pub fn new(opt: &Opt, ilias_url: &Url) -> Self {
+		let pages = Arc::new(TokenBucket::new(opt.rate, opt.burst));
+		RateLimiter {
+			ilias_host: ilias_url.host_str().unwrap_or_default().to_owned(),
+			downloads: Arc::new(TokenBucket::new(opt.download_rate, opt.burst).with_parent(Arc::clone(&pages))),
+			pages,
+			rate: opt.rate,
+			media_rate: opt.media_rate,
+			burst: opt.burst,
+			media: Mutex::new(HashMap::new()),
+			custom: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Buckets used for the request, taking the rates of the current task into account.
+	pub fn bucket(&self, url: &str, kind: RequestKind) -> Budget {
+		let host = Url::parse(url)
+			.ok()
+			.and_then(|x| x.host_str().map(str::to_owned))
+			.unwrap_or_default();
+		let rates = RATES.try_with(|x| *x).ok();
+		if host.is_empty() || host == self.ilias_host {
+			let bucket = match kind {
+				RequestKind::Page => Arc::clone(&self.pages),
+				RequestKind::Download => Arc::clone(&self.downloads),
+			};
+			let custom = rates.filter(|x| x.rate != self.rate).map(|x| self.custom(&self.ilias_host, x.rate));
+			return Budget { bucket, custom };
+		}
+		let custom = rates
+			.filter(|x| x.media_rate != self.media_rate)
+			.map(|x| self.custom(&host, x.media_rate));
+		let mut media = self.media.lock().unwrap();
+		let bucket = media
+			.entry(host)
+			.or_insert_with(|| Arc::new(TokenBucket::new(self.media_rate, self.burst)));
+		Budget {
+			bucket: Arc::clone(bucket),
+			custom,
+		}
+	}
+
+	fn custom(&self, host: &str, rate: usize) -> Arc<TokenBucket> {
+		let mut custom = self.custom.lock().unwrap();
+		let bucket = custom
+			.entry((host.to_owned(), rate))
+			.or_insert_with(|| Arc::new(TokenBucket::new(rate, self.burst)));
+		Arc::clone(bucket)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use structopt::StructOpt;
+
+	use super::*;
+
+	/// Seconds until the bucket allows the next request
+	async fn wait(bucket: &TokenBucket) -> f64 {
+		let start = Instant::now();
+		bucket.acquire().await;
+		start.elapsed().as_secs_f64()
+	}
+
+	fn assert_secs(actual: f64, expected: f64) {
+		assert!((actual - expected).abs() < 0.01, "waited {}s instead of {}s", actual, expected);
+	}
+
+	#[tokio::test]
+	async fn burst_and_refill() {
+		time::pause();
+		let bucket = TokenBucket::new(60, 3);
+		for _ in 0..3 {
+			assert_secs(wait(&bucket).await, 0.0);
+		}
+		assert_secs(wait(&bucket).await, 1.0);
+		// tokens are capped at the burst size
+		time::sleep(Duration::from_secs(100)).await;
+		for _ in 0..3 {
+			assert_secs(wait(&bucket).await, 0.0);
+		}
+		assert_secs(wait(&bucket).await, 1.0);
+	}
+
+	#[tokio::test]
+	async fn parent_budget() {
+		time::pause();
+		let parent = Arc::new(TokenBucket::new(6, 1));
+		let child = TokenBucket::new(60, 1).with_parent(Arc::clone(&parent));
+		assert_secs(wait(&child).await, 0.0);
+		// limited by the parent (10s per request), not the child
+		assert_secs(wait(&child).await, 10.0);
+		// the child used up the budget of the parent
+		assert_secs(wait(&parent).await, 10.0);
+	}
+
+	#[tokio::test]
+	async fn slow_down_and_speed_up() {
+		time::pause();
+		let parent = Arc::new(TokenBucket::new(60, 1));
+		let child = TokenBucket::new(60, 1).with_parent(Arc::clone(&parent));
+		assert_secs(wait(&child).await, 0.0);
+		child.slow_down();
+		assert_eq!(parent.state.lock().unwrap().slowdown, 0.5);
+		assert_secs(wait(&parent).await, 2.0);
+		for _ in 0..16 {
+			child.slow_down();
+		}
+		assert_eq!(child.state.lock().unwrap().slowdown, MIN_SLOWDOWN);
+		for _ in 0..50 {
+			child.speed_up();
+		}
+		assert_eq!(child.state.lock().unwrap().slowdown, 1.0);
+		assert_eq!(parent.state.lock().unwrap().slowdown, 1.0);
+		time::sleep(Duration::from_secs(10)).await;
+		assert_secs(wait(&parent).await, 0.0);
+		assert_secs(wait(&parent).await, 1.0);
+	}
+
+	#[tokio::test]
+	async fn buckets_per_host_and_rate() {
+		let opt = Opt::from_iter(&["KIT-ILIAS-downloader", "--output", "out", "--rate", "8", "--media-rate", "30"]);
+		let limiter = RateLimiter::new(&opt, &Url::parse("https://ilias.studium.kit.edu/").unwrap());
+		let page = limiter.bucket("https://ilias.studium.kit.edu/ilias.php", RequestKind::Page);
+		let download = limiter.bucket("https://ilias.studium.kit.edu/ilias.php", RequestKind::Download);
+		assert!(Arc::ptr_eq(&page.bucket, &limiter.pages));
+		assert!(Arc::ptr_eq(&download.bucket, &limiter.downloads));
+		assert!(Arc::ptr_eq(download.bucket.parent.as_ref().unwrap(), &limiter.pages));
+		assert!(page.custom.is_none());
+		// relative URLs belong to ILIAS
+		assert!(Arc::ptr_eq(&limiter.bucket("ilias.php?cmd=x", RequestKind::Page).bucket, &limiter.pages));
+
+		let media = limiter.bucket("https://oc.example.com/video.mp4", RequestKind::Download);
+		let same_host = limiter.bucket("https://oc.example.com/other.mp4", RequestKind::Download);
+		let other_host = limiter.bucket("https://media.example.com/video.mp4", RequestKind::Download);
+		assert!(Arc::ptr_eq(&media.bucket, &same_host.bucket));
+		assert!(!Arc::ptr_eq(&media.bucket, &other_host.bucket));
+		assert!(media.bucket.parent.is_none());
+
+		// lower rates of a course are applied in addition to the global budgets
+		let rates = Rates { rate: 2, media_rate: 30 };
+		let (page, media) = rates
+			.scope(async {
+				(
+					limiter.bucket("https://ilias.studium.kit.edu/ilias.php", RequestKind::Page),
+					limiter.bucket("https://oc.example.com/video.mp4", RequestKind::Download),
+				)
+			})
+			.await;
+		assert!(Arc::ptr_eq(&page.bucket, &limiter.pages));
+		assert_eq!(page.custom.as_ref().unwrap().rate, 2.0 / 60.0);
+		assert!(media.custom.is_none());
+	}
+}
@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use colored::Colorize;
+use indicatif::HumanBytes;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+
+use crate::errors::Category;
+use crate::shutdown;
+
+/// Result of processing an object
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+	/// Downloaded
+	Downloaded { bytes: u64 },
+	/// Contents listed (courses, folders, forums, ..), `bytes` of page HTML saved
+	Listed { bytes: u64 },
+	/// Local copy is up-to-date or the object is disabled by the options
+	Skipped,
+	/// Matched by .iliasignore or not supported
+	Ignored,
+}
+
+/// Report entry for a single object
+#[derive(Debug, Serialize)]
+pub struct Record {
+	pub kind: String,
+	pub url: String,
+	/// Local path, relative to the output directory
+	pub path: PathBuf,
+	pub action: &'static str,
+	pub bytes: u64,
+	/// Processing time in seconds
+	pub duration: f64,
+	/// Error message and its causes
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<Vec<String>>,
+	/// See [`Category`]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error_category: Option<&'static str>,
+}
+
+impl Record {
+	pub fn new(kind: String, url: String, path: PathBuf, result: &Result<Outcome>, duration: Duration) -> Self {
+		let error_category = result
+			.as_ref()
+			.err()
+			.filter(|e| !shutdown::is_interrupt(e))
+			.map(|e| Category::of(e).name());
+		let (action, bytes, error) = match result {
+			Ok(Outcome::Downloaded { bytes }) => ("downloaded", *bytes, None),
+			Ok(Outcome::Listed { bytes }) => ("listed", *bytes, None),
+			Ok(Outcome::Skipped) => ("skipped", 0, None),
+			Ok(Outcome::Ignored) => ("ignored", 0, None),
+			Err(e) if shutdown::is_interrupt(e) => ("interrupted", 0, Some(e.chain().map(|x| x.to_string()).collect())),
+			Err(e) => ("failed", 0, Some(e.chain().map(|x| x.to_string()).collect())),
+		};
+		Record {
+			kind,
+			url,
+			path,
+			action,
+			bytes,
+			duration: duration.as_secs_f64(),
+			error,
+			error_category,
+		}
+	}
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+	downloaded: usize,
+	listed: usize,
+	skipped: usize,
+	ignored: usize,
+	failed: usize,
+	interrupted: usize,
+	bytes: u64,
+}
+
+impl Summary {
+	fn add(&mut self, record: &Record) {
+		match record.action {
+			"downloaded" => self.downloaded += 1,
+			"listed" => self.listed += 1,
+			"skipped" => self.skipped += 1,
+			"ignored" => self.ignored += 1,
+			"interrupted" => self.interrupted += 1,
+			_ => self.failed += 1,
+		}
+		self.bytes += record.bytes;
+	}
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+	started: String,
+	/// Run time in seconds
+	duration: f64,
+	summary: Summary,
+	objects: &'a [Record],
+}
+
+static STARTED: Lazy<DateTime<Local>> = Lazy::new(Local::now);
+/// Records collected for `--report`
+static RECORDS: OnceCell<Mutex<Vec<Record>>> = OnceCell::new();
+/// File specified by `--report-events`
+static EVENTS: OnceCell<Mutex<File>> = OnceCell::new();
+/// Totals of all processed objects, printed if the run is interrupted
+static TOTALS: Lazy<Mutex<Summary>> = Lazy::new(|| Mutex::new(Summary::default()));
+
+/// Start the run, opening the events file (if specified).
+pub fn init(report: bool, events: Option<&Path>) -> Result<()> {
+	Lazy::force(&STARTED);
+	if report {
+		let _ = RECORDS.set(Mutex::new(Vec::new()));
+	}
+	if let Some(path) = events {
+		let file = File::create(path).context("failed to create events file")?;
+		let _ = EVENTS.set(Mutex::new(file));
+	}
+	Ok(())
+}
+
+pub fn record(record: Record) {
+	TOTALS.lock().unwrap().add(&record);
+	if let Some(events) = EVENTS.get() {
+		let result = serde_json::to_string(&record)
+			.map_err(anyhow::Error::from)
+			.and_then(|line| Ok(writeln!(events.lock().unwrap(), "{}", line)?));
+		if let Err(e) = result {
+			warning!("failed to write event:", e);
+		}
+	}
+	if let Some(records) = RECORDS.get() {
+		records.lock().unwrap().push(record);
+	}
+}
+
+/// Write the JSON report of all processed objects.
+pub fn write(path: &Path) -> Result<()> {
+	let mut records = RECORDS.get().context("report not initialized")?.lock().unwrap();
+	records.sort_by(|a, b| a.path.cmp(&b.path));
+	let mut summary = Summary::default();
+	for record in records.iter() {
+		summary.add(record);
+	}
+	let report = Report {
+		started: STARTED.to_rfc3339(),
+		duration: (Local::now() - *STARTED).num_milliseconds() as f64 / 1000.0,
+		summary,
+		objects: &records,
+	};
+	let file = File::create(path).context("failed to create report file")?;
+	serde_json::to_writer_pretty(file, &report).context("failed to write report")?;
+	Ok(())
+}
+
+/// Print the number of objects processed so far.
+pub fn print_summary() {
+	let totals = TOTALS.lock().unwrap();
+	log!(
+		0,
+		"Processed {} objects: {} downloaded ({}), {} listed, {} skipped, {} ignored, {} failed, {} interrupted",
+		totals.downloaded + totals.listed + totals.skipped + totals.ignored + totals.failed + totals.interrupted,
+		totals.downloaded,
+		HumanBytes(totals.bytes),
+		totals.listed,
+		totals.skipped,
+		totals.ignored,
+		totals.failed,
+		totals.interrupted
+	);
+}
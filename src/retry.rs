@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::error::Error as _;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Response, StatusCode};
+
+/// Delay before the first retry, doubled for every further attempt
+const BASE_DELAY: Duration = Duration::from_secs(1);
+/// Upper limit for the backoff and `Retry-After` delays
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Returns true if the error is caused by:
+/// "http2 error: protocol error: not a result of an error"
+fn error_is_http2(error: &reqwest::Error) -> bool {
+	error.source() // hyper::Error
+		.map(|x| x.source()) // -> h2::Error
+		.flatten()
+		.map(|x| x.downcast_ref::<h2::Error>())
+		.flatten()
+		.map(|x| x.reason())
+		.flatten()
+		.map(|x| x == h2::Reason::NO_ERROR)
+		.unwrap_or(false)
+}
+
+/// Returns true if the error is caused by the connection being closed unexpectedly.
+fn error_is_connection_reset(error: &reqwest::Error) -> bool {
+	let mut source = error.source();
+	while let Some(err) = source {
+		if let Some(err) = err.downcast_ref::<io::Error>() {
+			return matches!(
+				err.kind(),
+				io::ErrorKind::ConnectionReset
+					| io::ErrorKind::ConnectionAborted
+					| io::ErrorKind::BrokenPipe
+					| io::ErrorKind::UnexpectedEof
+			);
+		}
+		source = err.source();
+	}
+	false
+}
+
+/// Describe why the request should be retried, or None if the result is final.
+pub fn retry_reason(result: &Result<Response, reqwest::Error>) -> Option<String> {
+	match result {
+		Ok(response) => match response.status() {
+			StatusCode::TOO_MANY_REQUESTS
+			| StatusCode::BAD_GATEWAY
+			| StatusCode::SERVICE_UNAVAILABLE
+			| StatusCode::GATEWAY_TIMEOUT => Some(format!("HTTP status {}", response.status())),
+			_ => None,
+		},
+		Err(e) => error_reason(e).map(str::to_owned),
+	}
+}
+
+/// Describe why the failed request or transfer should be retried, or None if the error is permanent.
+pub fn error_reason(error: &reqwest::Error) -> Option<&'static str> {
+	if error.is_timeout() {
+		Some("timeout")
+	} else if error.is_connect() {
+		Some("connection failed")
+	} else if error_is_http2(error) {
+		Some("HTTP/2 NO_ERROR")
+	} else if error_is_connection_reset(error) {
+		Some("connection reset")
+	} else if error.is_body() {
+		Some("transfer interrupted")
+	} else {
+		None
+	}
+}
+
+/// Delay requested by the server using the `Retry-After` header (in seconds or as HTTP date).
+fn retry_after(response: &Response) -> Option<Duration> {
+	let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+	if let Ok(seconds) = value.parse::<u64>() {
+		return Some(Duration::from_secs(seconds));
+	}
+	let date = DateTime::parse_from_rfc2822(value).ok()?;
+	Some((date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Exponential backoff with jitter (50% - 100% of the delay).
+pub fn backoff(attempt: u32) -> Duration {
+	let delay = BASE_DELAY
+		.checked_mul(1 << attempt.saturating_sub(1).min(16))
+		.unwrap_or(MAX_DELAY)
+		.min(MAX_DELAY);
+	let nanos = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|x| x.subsec_nanos())
+		.unwrap_or_default();
+	delay.mul_f64(0.5 + f64::from(nanos % 1000) / 2000.0)
+}
+
+/// Time to wait before the next attempt, `attempt` is the number of the failed attempt.
+pub fn retry_delay(attempt: u32, result: &Result<Response, reqwest::Error>) -> Duration {
+	result
+		.as_ref()
+		.ok()
+		.and_then(retry_after)
+		.map(|x| x.min(MAX_DELAY))
+		.unwrap_or_else(|| backoff(attempt))
+}
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use colored::Colorize;
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tokio::task;
+
+use crate::ilias::IliasError;
+
+/// Exit code of interrupted runs (128 + SIGINT)
+pub const EXIT_CODE: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static NOTIFY: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Whether the run was interrupted, no further objects should be processed.
+pub fn requested() -> bool {
+	REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Completes once the run is interrupted.
+pub async fn wait() {
+	// registered before checking the flag, so the notification can't be missed
+	let notified = NOTIFY.notified();
+	if requested() {
+		return;
+	}
+	notified.await;
+}
+
+/// Whether the error was caused by cancelling a request or download after the run was interrupted.
+pub fn is_interrupt(error: &anyhow::Error) -> bool {
+	error
+		.chain()
+		.any(|x| matches!(x.downcast_ref::<IliasError>(), Some(IliasError::Interrupted)))
+}
+
+/// Handle Ctrl-C (and SIGTERM on Unix): the first signal stops the run gracefully,
+/// the second one exits immediately.
+pub fn listen() -> io::Result<()> {
+	let mut signals = Signals::new()?;
+	task::spawn(async move {
+		signals.recv().await;
+		REQUESTED.store(true, Ordering::SeqCst);
+		NOTIFY.notify_waiters();
+		warning!(format => "interrupted, finishing up.. (press Ctrl-C again to quit immediately)");
+		signals.recv().await;
+		std::process::exit(EXIT_CODE);
+	});
+	Ok(())
+}
+
+struct Signals {
+	#[cfg(unix)]
+	interrupt: tokio::signal::unix::Signal,
+	#[cfg(unix)]
+	terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+	#[cfg(unix)]
+	fn new() -> io::Result<Self> {
+		use tokio::signal::unix::{signal, SignalKind};
+		Ok(Signals {
+			interrupt: signal(SignalKind::interrupt())?,
+			terminate: signal(SignalKind::terminate())?,
+		})
+	}
+
+	#[cfg(not(unix))]
+	fn new() -> io::Result<Self> {
+		Ok(Signals {})
+	}
+
+	#[cfg(unix)]
+	async fn recv(&mut self) {
+		tokio::select! {
+			_ = self.interrupt.recv() => {},
+			_ = self.terminate.recv() => {},
+		}
+	}
+
+	#[cfg(not(unix))]
+	async fn recv(&mut self) {
+		let _ = tokio::signal::ctrl_c().await;
+	}
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::ilias::{FileMeta, Object};
+use crate::util::write_file_data;
+
+/// Information about a synced ILIAS object
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+	/// See [`Object::id`]
+	pub id: String,
+	pub kind: String,
+	/// Local path, relative to the output directory
+	pub path: PathBuf,
+	/// ID of the containing object (used for forum posts)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub parent: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub size: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub version: Option<u32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub date: Option<String>,
+	/// Number of the last run that encountered this object
+	pub last_seen: u64,
+}
+
+impl Entry {
+	pub fn new(id: impl Into<String>, kind: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+		Entry {
+			id: id.into(),
+			kind: kind.into(),
+			path: path.into(),
+			parent: None,
+			size: None,
+			version: None,
+			date: None,
+			last_seen: 0,
+		}
+	}
+
+	pub fn from_object(obj: &Object, path: &Path) -> Self {
+		Entry::new(obj.id(), obj.kind(), path)
+	}
+
+	pub fn with_meta(mut self, meta: &FileMeta) -> Self {
+		self.version = meta.version;
+		self.date = meta.date.clone();
+		self
+	}
+}
+
+/// Persistent record of all synced objects, stored in the output directory.
+/// One JSON object per line.
+pub struct SyncState {
+	file: PathBuf,
+	/// Number of the current run
+	run: u64,
+	entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl SyncState {
+	pub fn load(output: &Path) -> Self {
+		let file = output.join(".iliasstate");
+		let mut entries = HashMap::new();
+		if let Ok(text) = std::fs::read_to_string(&file) {
+			for line in text.lines().filter(|x| !x.trim().is_empty()) {
+				match serde_json::from_str::<Entry>(line) {
+					Ok(entry) => {
+						entries.insert(entry.id.clone(), entry);
+					},
+					Err(e) => {
+						warning!("ignoring invalid line in .iliasstate:", e);
+					},
+				}
+			}
+		}
+		let run = entries.values().map(|x| x.last_seen).max().unwrap_or(0) + 1;
+		SyncState {
+			file,
+			run,
+			entries: Mutex::new(entries),
+		}
+	}
+
+	pub fn get(&self, id: &str) -> Option<Entry> {
+		self.entries.lock().unwrap().get(id).cloned()
+	}
+
+	/// Insert or replace the entry, marking it as seen in this run.
+	pub fn insert(&self, mut entry: Entry) {
+		entry.last_seen = self.run;
+		self.entries.lock().unwrap().insert(entry.id.clone(), entry);
+	}
+
+	/// Entry of the object stored at the specified path.
+	pub fn entry_at(&self, path: &Path) -> Option<Entry> {
+		self.entries
+			.lock()
+			.unwrap()
+			.values()
+			.find(|x| x.path == path)
+			.cloned()
+	}
+
+	/// Kind of the object stored at the specified path.
+	pub fn kind_at(&self, path: &Path) -> Option<String> {
+		self.entry_at(path).map(|x| x.kind)
+	}
+
+	/// Whether the entry was already encountered in this run.
+	pub fn seen_in_this_run(&self, entry: &Entry) -> bool {
+		entry.last_seen == self.run
+	}
+
+	/// Update the paths of all entries located at or below `from`.
+	pub fn move_path(&self, from: &Path, to: &Path) {
+		for entry in self.entries.lock().unwrap().values_mut() {
+			if let Ok(rest) = entry.path.strip_prefix(from) {
+				entry.path = to.join(rest);
+			}
+		}
+	}
+
+	/// Number of entries contained in the specified object.
+	pub fn child_count(&self, parent: &str) -> usize {
+		self.entries
+			.lock()
+			.unwrap()
+			.values()
+			.filter(|x| x.parent.as_deref() == Some(parent))
+			.count()
+	}
+
+	pub async fn save(&self) -> Result<()> {
+		let data = {
+			let entries = self.entries.lock().unwrap();
+			let mut entries = entries.values().collect::<Vec<_>>();
+			entries.sort_by(|a, b| a.id.cmp(&b.id));
+			let mut data = String::new();
+			for entry in entries {
+				data += &serde_json::to_string(entry)?;
+				data.push('\n');
+			}
+			data
+		};
+		write_file_data(&self.file, &mut data.as_bytes()).await.context("failed to save .iliasstate")?;
+		Ok(())
+	}
+}
@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+
+/// Validity of a code in seconds
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+
+/// Time-based one-time password (RFC 6238) for the base32 encoded secret, valid right now.
+pub fn code(secret: &str) -> Result<String> {
+	let key = decode(secret)?;
+	let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+	Ok(totp(&key, time, DIGITS))
+}
+
+/// Decode the secret, ignoring spaces, case and padding.
+fn decode(secret: &str) -> Result<Vec<u8>> {
+	let secret = secret.replace(' ', "").to_ascii_uppercase();
+	base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret.trim_end_matches('='))
+		.ok_or_else(|| anyhow!("TOTP secret is not valid base32"))
+}
+
+/// Code with the specified number of digits at the Unix time `time` (HMAC-SHA-1).
+fn totp(key: &[u8], time: u64, digits: u32) -> String {
+	let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+	mac.update(&(time / STEP).to_be_bytes());
+	let hash = mac.finalize().into_bytes();
+	// dynamic truncation
+	let offset = (hash[hash.len() - 1] & 0xf) as usize;
+	let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+	format!("{:0width$}", value % 10u32.pow(digits), width = digits as usize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Key of the SHA-1 test vectors in RFC 6238, appendix B
+	const KEY: &[u8] = b"12345678901234567890";
+
+	#[test]
+	fn rfc6238_vectors() {
+		assert_eq!(totp(KEY, 59, 8), "94287082");
+		assert_eq!(totp(KEY, 1111111109, 8), "07081804");
+		assert_eq!(totp(KEY, 1111111111, 8), "14050471");
+		assert_eq!(totp(KEY, 1234567890, 8), "89005924");
+		assert_eq!(totp(KEY, 2000000000, 8), "69279037");
+		assert_eq!(totp(KEY, 20000000000, 8), "65353130");
+	}
+
+	#[test]
+	fn six_digits() {
+		assert_eq!(totp(KEY, 59, 6), "287082");
+		assert_eq!(totp(KEY, 1111111109, 6), "081804");
+	}
+
+	#[test]
+	fn decode_secret() {
+		assert_eq!(decode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(), KEY);
+		assert_eq!(decode("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(), KEY);
+		assert_eq!(decode("MFRGG===").unwrap(), b"abc");
+		assert!(decode("not base32!").is_err());
+	}
+}
@@ -1,22 +1,213 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 use anyhow::Context;
-use tokio::fs::File as AsyncFile;
-use tokio::io::{AsyncRead, BufWriter};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use filetime::FileTime;
+use colored::Colorize;
+use futures_util::StreamExt;
+use ignore::WalkBuilder;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, LAST_MODIFIED};
+use serde_json::json;
+use tokio::fs::{File as AsyncFile, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWriteExt, BufWriter};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::Result;
+use crate::{ILIAS, IliasError, Result, retry};
+use crate::bandwidth::Throttle;
+use crate::{progress, shutdown};
 
-/// Write all data to the specified path. Will overwrite previous file data.
-pub async fn write_file_data<R: ?Sized>(path: impl AsRef<Path>, data: &mut R) -> Result<()> 
-where R: AsyncRead + Unpin {
-	let file = AsyncFile::create(path.as_ref()).await.context("failed to create file")?;
+/// Suffix of files that are still being written
+const TEMP_SUFFIX: &str = ".ilias-tmp";
+/// Suffix of partially downloaded files, kept to resume the download later
+const PART_SUFFIX: &str = ".ilias-part";
+/// Suffix of the file storing the validators of a partial download
+const PART_META_SUFFIX: &str = ".ilias-part.json";
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut name = path.file_name().map(|x| x.to_owned()).unwrap_or_default();
+	name.push(suffix);
+	path.with_file_name(name)
+}
+
+/// Whether the file name belongs to a temporary file or partial download.
+pub fn is_temp_file(name: &str) -> bool {
+	name.ends_with(TEMP_SUFFIX) || name.ends_with(PART_SUFFIX) || name.ends_with(PART_META_SUFFIX)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+	with_suffix(path, TEMP_SUFFIX)
+}
+
+/// Extract the headers used to check whether a partial download can be resumed.
+fn validators(headers: &HeaderMap) -> serde_json::Value {
+	let header = |name| headers.get(name).and_then(|x: &reqwest::header::HeaderValue| x.to_str().ok());
+	json!({
+		"etag": header(ETAG),
+		"last_modified": header(LAST_MODIFIED),
+		"length": header(CONTENT_LENGTH),
+	})
+}
+
+/// First byte of the range sent in a 206 response (`Content-Range: bytes START-END/TOTAL`).
+fn range_start(headers: &HeaderMap) -> Option<u64> {
+	let range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+	range.trim().strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+/// Download the URL to the specified path.
+/// Interrupted downloads are kept and resumed using Range requests, if the server supports them.
+/// Stalled or interrupted transfers are resumed after a backoff delay (up to `--max-attempts` times).
+/// Returns the number of bytes downloaded.
+pub async fn download_resumable(ilias: &ILIAS, url: &str, path: &Path) -> Result<u64> {
+	let mut bytes = 0;
+	let mut attempt = 1;
+	let mut progress = progress::Download::new(path);
+	loop {
+		let e = match download_part(ilias, url, path, &mut bytes, &mut progress).await {
+			Ok(()) => return Ok(bytes),
+			Err(e) => e,
+		};
+		let reason = e.downcast_ref::<IliasError>().and_then(|x| match x {
+			IliasError::Stalled(_) => Some("download stalled"),
+			IliasError::Http(e) => retry::error_reason(e),
+			_ => None,
+		});
+		match reason {
+			Some(reason) if attempt < ilias.opt.max_attempts && !shutdown::requested() => {
+				let delay = retry::backoff(attempt as u32);
+				warning!(format => "{}: {}, resuming in {:.1}s (attempt {}/{})", path.display(), reason, delay.as_secs_f64(), attempt + 1, ilias.opt.max_attempts);
+				tokio::select! {
+					_ = tokio::time::sleep(delay) => {},
+					_ = shutdown::wait() => return Err(IliasError::Interrupted.into()),
+				}
+				attempt += 1;
+			},
+			_ => return Err(e),
+		}
+	}
+}
+
+/// Single attempt of [`download_resumable`], adding the received data to `bytes`.
+async fn download_part(ilias: &ILIAS, url: &str, path: &Path, bytes: &mut u64, progress: &mut progress::Download) -> Result<()> {
+	let part = with_suffix(path, PART_SUFFIX);
+	let meta = with_suffix(path, PART_META_SUFFIX);
+	let mut offset = 0;
+	if let (Ok(part_meta), Ok(saved)) = (tokio::fs::metadata(&part).await, tokio::fs::read_to_string(&meta).await) {
+		let head = ilias.head(url).await.context("HEAD request failed")?;
+		let accepts_ranges = head.headers().get(ACCEPT_RANGES).map(|x| x == "bytes").unwrap_or(false);
+		let current = validators(head.headers());
+		let unchanged = serde_json::from_str::<serde_json::Value>(&saved).ok().as_ref() == Some(&current)
+			&& !current["length"].is_null()
+			&& !(current["etag"].is_null() && current["last_modified"].is_null());
+		if accepts_ranges && unchanged {
+			offset = part_meta.len();
+			log!(1, "Resuming download of {} at {} bytes", path.display(), offset);
+		} else {
+			log!(1, "Can't resume download of {}, starting from scratch", path.display());
+		}
+	}
+	let mut resp = ilias.download_from(url, offset).await?;
+	if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+		offset = 0;
+		resp = ilias.download_from(url, 0).await?;
+	}
+	if offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT && range_start(resp.headers()) != Some(offset) {
+		// appending a different range would corrupt the file
+		log!(1, "Unexpected range received for {}, starting from scratch", path.display());
+		offset = 0;
+		resp = ilias.download_from(url, 0).await?;
+	}
+	let file = if offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT {
+		progress.start(offset, resp.content_length().map(|x| x + offset));
+		OpenOptions::new().append(true).open(&part).await.context("failed to open partial download")?
+	} else {
+		progress.start(0, resp.content_length());
+		tokio::fs::write(&meta, validators(resp.headers()).to_string()).await.context("failed to write download metadata")?;
+		AsyncFile::create(&part).await.context("failed to create file")?
+	};
 	let mut file = BufWriter::new(file);
-	tokio::io::copy(data, &mut file).await.context("failed to write to file")?;
+	let mut stream = resp.bytes_stream();
+	let throttle = Throttle::new(ilias.opt.max_bandwidth_per_download.clone());
+	// data already written to the file is kept if the transfer fails
+	loop {
+		let next = tokio::select! {
+			next = tokio::time::timeout(ilias.stall_timeout(), stream.next()) => next,
+			_ = shutdown::wait() => {
+				// keep the partial download, it is resumed in the next run
+				file.flush().await.context("failed to write to file")?;
+				return Err(IliasError::Interrupted.into());
+			},
+		};
+		let chunk = match next {
+			Ok(Some(chunk)) => chunk.map_err(IliasError::Http)?,
+			Ok(None) => break,
+			Err(_) => return Err(IliasError::Stalled(ilias.stall_timeout()).into()),
+		};
+		file.write_all(&chunk).await.context("failed to write to file")?;
+		*bytes += chunk.len() as u64;
+		progress.inc(chunk.len() as u64);
+		ilias.bandwidth.consume(chunk.len()).await;
+		throttle.consume(chunk.len()).await;
+	}
+	file.flush().await.context("failed to write to file")?;
+	drop(file);
+	tokio::fs::rename(&part, path).await.context("failed to rename partial download")?;
+	let _ = tokio::fs::remove_file(&meta).await;
 	Ok(())
 }
 
+/// Write all data to the specified path. Will overwrite previous file data.
+/// The data is written to a temporary file first, which is renamed after all data is written.
+/// Returns the number of bytes written.
+pub async fn write_file_data<R: ?Sized>(path: impl AsRef<Path>, data: &mut R) -> Result<u64> 
+where R: AsyncRead + Unpin {
+	let path = path.as_ref();
+	let temp = temp_path(path);
+	let result = async {
+		let file = AsyncFile::create(&temp).await.context("failed to create file")?;
+		let mut file = BufWriter::new(file);
+		let bytes = tokio::io::copy(data, &mut file).await.context("failed to write to file")?;
+		file.flush().await.context("failed to write to file")?;
+		drop(file);
+		tokio::fs::rename(&temp, path).await.context("failed to rename temporary file")?;
+		Ok(bytes)
+	}.await;
+	if result.is_err() {
+		let _ = tokio::fs::remove_file(&temp).await;
+	}
+	result
+}
+
+/// Remove temporary files left behind by interrupted downloads.
+pub fn remove_temp_files(dir: &Path) {
+	for entry in WalkBuilder::new(dir).standard_filters(false).build() {
+		let entry = match entry {
+			Ok(entry) => entry,
+			Err(e) => {
+				warning!(e);
+				continue;
+			},
+		};
+		if entry.file_type().map(|x| x.is_file()).unwrap_or(false)
+			&& entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX)
+		{
+			log!(1, "Removing temporary file {}", entry.path().display());
+			if let Err(e) = std::fs::remove_file(entry.path()) {
+				warning!(e);
+			}
+		}
+	}
+}
+
+/// Size of the file, if it exists.
+pub async fn file_size(path: &Path) -> Option<u64> {
+	tokio::fs::metadata(path).await.ok().map(|x| x.len())
+}
+
 /// Create a directory. Does not error if the directory already exists.
 pub async fn create_dir(path: &Path) -> Result<()> {
 	if let Err(e) = tokio::fs::create_dir(&path).await {
@@ -27,8 +218,141 @@ pub async fn create_dir(path: &Path) -> Result<()> {
 	Ok(())
 }
 
+static DATE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(
+	r#"(?i)(heute|today|gestern|yesterday|(\d{1,2})\.\s*(\d{1,2}\.|\p{L}+)\s*(\d{4}))[,\s-]*(\d{1,2}):(\d{2})"#
+).unwrap());
+
+/// Parse a date as displayed by ILIAS, e.g. "17. Mai 2021, 10:32", "Heute, 10:32" or "17.05.2021 - 10:32".
+pub fn parse_date(text: &str) -> Option<NaiveDateTime> {
+	let captures = DATE_REGEX.captures(text)?;
+	let today = Local::now().naive_local().date();
+	let date = match &*captures[1].to_lowercase() {
+		"heute" | "today" => today,
+		"gestern" | "yesterday" => today - chrono::Duration::days(1),
+		_ => {
+			let day = captures[2].parse().ok()?;
+			let month = captures[3].trim_end_matches('.').to_lowercase();
+			let month = month.parse().ok().or_else(|| {
+				const MONTHS: &[&[&str]] = &[
+					&["jan"], &["feb"], &["mär", "mar", "mrz"], &["apr"], &["mai", "may"], &["jun"],
+					&["jul"], &["aug"], &["sep"], &["okt", "oct"], &["nov"], &["dez", "dec"],
+				];
+				MONTHS
+					.iter()
+					.position(|prefixes| prefixes.iter().any(|x| month.starts_with(x)))
+					.map(|x| x as u32 + 1)
+			})?;
+			NaiveDate::from_ymd_opt(captures[4].parse().ok()?, month, day)?
+		},
+	};
+	date.and_hms_opt(captures[5].parse().ok()?, captures[6].parse().ok()?, 0)
+}
+
+static SIZE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^(\d+(?:[.,]\d+)?)\s*(Bytes|KB|MB|GB)$"#).unwrap());
+
+/// Parse a file size as displayed by ILIAS, e.g. "1,4 MB" or "512 Bytes".
+pub fn parse_size(text: &str) -> Option<u64> {
+	let captures = SIZE_REGEX.captures(text)?;
+	let number = captures[1].replace(',', ".").parse::<f64>().ok()?;
+	let unit = match &captures[2] {
+		"KB" => 1 << 10,
+		"MB" => 1 << 20,
+		"GB" => 1 << 30,
+		_ => 1,
+	};
+	Some((number * unit as f64) as u64)
+}
+
+/// Set the modification time of the file to the date displayed by ILIAS (if it can be parsed).
+pub fn set_modified(path: &Path, date: Option<&str>) -> Result<()> {
+	if let Some(date) = date.and_then(parse_date) {
+		let date = Local.from_local_datetime(&date).earliest().context("invalid local time")?;
+		filetime::set_file_mtime(path, FileTime::from_unix_time(date.timestamp(), 0))
+			.context("failed to set modification time")?;
+	}
+	Ok(())
+}
+
+/// Insert a suffix before the file extension: name.pdf => name_suffix.pdf
+pub fn versioned_name(name: &str, suffix: &str) -> String {
+	match name.rsplit_once('.') {
+		Some((name, extension)) => format!("{}_{}.{}", name, suffix, extension),
+		None => format!("{}_{}", name, suffix),
+	}
+}
+
 const INVALID: &[char] = &['/', '\\', ':', '<', '>', '"', '|', '?', '*', '\n', '\t'];
 
 pub fn file_escape(s: &str) -> String {
 	s.replace(INVALID, "_")
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn datetime(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+		NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+	}
+
+	#[test]
+	fn parse_date_formats() {
+		assert_eq!(parse_date("17. Mai 2021, 10:32"), Some(datetime(2021, 5, 17, 10, 32)));
+		assert_eq!(parse_date("3. März 2020, 08:05"), Some(datetime(2020, 3, 3, 8, 5)));
+		assert_eq!(parse_date("1. Dez 2019, 23:59"), Some(datetime(2019, 12, 1, 23, 59)));
+		assert_eq!(parse_date("17. May 2021, 10:32"), Some(datetime(2021, 5, 17, 10, 32)));
+		assert_eq!(parse_date("5. Oct 2021, 9:00"), Some(datetime(2021, 10, 5, 9, 0)));
+		assert_eq!(parse_date("17.05.2021 - 10:32"), Some(datetime(2021, 5, 17, 10, 32)));
+		// forum post author line
+		assert_eq!(parse_date("Max Mustermann | 17. Mai 2021, 10:32"), Some(datetime(2021, 5, 17, 10, 32)));
+	}
+
+	#[test]
+	fn content_range_start() {
+		let headers = |value: &'static str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(CONTENT_RANGE, value.parse().unwrap());
+			headers
+		};
+		assert_eq!(range_start(&headers("bytes 1000-1999/2000")), Some(1000));
+		assert_eq!(range_start(&headers("bytes 0-1999/*")), Some(0));
+		assert_eq!(range_start(&headers("bytes */2000")), None);
+		assert_eq!(range_start(&HeaderMap::new()), None);
+	}
+
+	#[test]
+	fn parse_date_relative() {
+		let today = Local::now().naive_local().date();
+		assert_eq!(parse_date("Heute, 10:32"), Some(today.and_hms_opt(10, 32, 0).unwrap()));
+		assert_eq!(parse_date("Today, 10:32"), Some(today.and_hms_opt(10, 32, 0).unwrap()));
+		let yesterday = today - chrono::Duration::days(1);
+		assert_eq!(parse_date("Gestern, 07:00"), Some(yesterday.and_hms_opt(7, 0, 0).unwrap()));
+		assert_eq!(parse_date("yesterday, 07:00"), Some(yesterday.and_hms_opt(7, 0, 0).unwrap()));
+	}
+
+	#[test]
+	fn parse_date_invalid() {
+		assert_eq!(parse_date(""), None);
+		assert_eq!(parse_date("Version 2"), None);
+		assert_eq!(parse_date("31. Foo 2021, 10:00"), None);
+		assert_eq!(parse_date("31.02.2021 - 10:00"), None);
+	}
+
+	#[test]
+	fn parse_size_units() {
+		assert_eq!(parse_size("512 Bytes"), Some(512));
+		assert_eq!(parse_size("2 KB"), Some(2048));
+		assert_eq!(parse_size("1,5 MB"), Some(3 << 19));
+		assert_eq!(parse_size("1.5 MB"), Some(3 << 19));
+		assert_eq!(parse_size("2GB"), Some(2 << 30));
+		assert_eq!(parse_size("1,4 TB"), None);
+		assert_eq!(parse_size("MB"), None);
+	}
+
+	#[test]
+	fn versioned_names() {
+		assert_eq!(versioned_name("Sheet.pdf", "v2"), "Sheet_v2.pdf");
+		assert_eq!(versioned_name("archive.tar.gz", "v3"), "archive.tar_v3.gz");
+		assert_eq!(versioned_name("README", "v1_2021-05-17"), "README_v1_2021-05-17");
+	}
+}
@@ -1,3 +1,4 @@
 #!/bin/sh
 
-downloader --output /usr/downloader -U $USERNAME -P $PASSWORD --sync-url $SYNC_URL --rate $RATE $EXTRA_ARGS
\ No newline at end of file
+# the credentials are read from the ILIAS_USERNAME and ILIAS_PASSWORD environment variables
+downloader --output /usr/downloader --sync-url $SYNC_URL --rate $RATE $EXTRA_ARGS
\ No newline at end of file